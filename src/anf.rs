@@ -0,0 +1,631 @@
+//! An intermediate representation in A-normal form.
+//!
+//! Every intermediate value is bound by a `let` to a name of its own, and
+//! every operation takes atoms: literals and names. Where control flow
+//! meets again after an `if` or a comparison chain, the rest of the
+//! computation is a named continuation, a `join` point the branches jump
+//! to. Names are unique within a term, so a pass never has to worry about
+//! shadowing.
+
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use anyhow::{bail, Ok, Result};
+
+use crate::{
+    expression::Expr,
+    ident::Ident,
+    internal_value::Value,
+    operator::{BinOp, UnOp},
+};
+
+/// A unique name: a variable of the source, or a temporary or continuation
+/// introduced by the lowering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Name {
+    pub source: Option<Ident>,
+    pub id: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Atom {
+    Int(i64),
+    Bool(bool),
+    Name(Name),
+}
+
+/// The right-hand side of a `let`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Prim {
+    Atom(Atom),
+    BinOp(BinOp, Atom, Atom),
+    UnaryOp(UnOp, Atom),
+    /// A function, which may refer to itself by the name it is bound to.
+    Lambda(Rc<Fun>),
+    App(Atom, Atom),
+    Compose(Atom, Atom),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Fun {
+    pub param: Name,
+    /// The names of enclosing scopes the body uses, which a closure of the
+    /// function captures; not the name the function itself is bound to.
+    pub free: Vec<Name>,
+    pub body: Term,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Term {
+    Let(Name, Prim, Box<Term>),
+    /// `join k(x) { .. }; ..`: defines the continuation `k` for the term
+    /// that follows.
+    Join(Name, Name, Box<Term>, Box<Term>),
+    /// Passes a value to a continuation.
+    Jump(Name, Atom),
+    If(Atom, Box<Term>, Box<Term>),
+    Return(Atom),
+    TailCall(Atom, Atom),
+    /// A name bound nowhere; evaluating it is an error.
+    Unbound(Ident),
+}
+
+/// Lowers the rest of the computation, given the value.
+type Rest<'a> = Box<dyn FnOnce(&mut Lower, Atom) -> Term + 'a>;
+
+/// What to do with the value of the expression being lowered.
+enum Ctx<'a> {
+    Return,
+    Jump(Name),
+    Bind(Rest<'a>),
+}
+
+impl Ctx<'_> {
+    /// A copy of a context that can be lowered in more than one place.
+    fn dup(&self) -> Option<Ctx<'static>> {
+        match self {
+            Ctx::Return => Some(Ctx::Return),
+            Ctx::Jump(k) => Some(Ctx::Jump(*k)),
+            Ctx::Bind(_) => None,
+        }
+    }
+}
+
+/// Lowers type-checked expressions to terms.
+pub struct Lower {
+    /// The names of the variables in scope, innermost last.
+    scope: Vec<(Ident, Name)>,
+    /// The functions being lowered, innermost last: where their scope
+    /// starts, and the names from outside it that they use.
+    funs: Vec<(usize, Vec<Name>)>,
+    next: usize,
+}
+
+impl Lower {
+    pub fn lower(expr: &Expr) -> Term {
+        let mut lower = Lower {
+            scope: vec![],
+            funs: vec![],
+            next: 0,
+        };
+        lower.term(expr, Ctx::Return)
+    }
+
+    fn fresh(&mut self, source: Option<Ident>) -> Name {
+        self.next += 1;
+        Name {
+            source,
+            id: self.next - 1,
+        }
+    }
+
+    fn bind(&mut self, name: Ident) -> Name {
+        let fresh = self.fresh(Some(name));
+        self.scope.push((name, fresh));
+        fresh
+    }
+
+    fn finish(&mut self, ctx: Ctx, atom: Atom) -> Term {
+        match ctx {
+            Ctx::Return => Term::Return(atom),
+            Ctx::Jump(k) => Term::Jump(k, atom),
+            Ctx::Bind(rest) => rest(self, atom),
+        }
+    }
+
+    /// Binds the value of `prim` to a temporary for the rest.
+    fn let_prim(&mut self, prim: Prim, ctx: Ctx) -> Term {
+        let temp = self.fresh(None);
+        let rest = self.finish(ctx, Atom::Name(temp));
+        Term::Let(temp, prim, Box::new(rest))
+    }
+
+    /// Lowers `expr`, handing its value to `ctx`. The scope is the same
+    /// when `ctx` gets the value and afterwards as before.
+    fn term<'a>(&mut self, expr: &'a Expr, ctx: Ctx<'a>) -> Term {
+        let depth = self.scope.len();
+        let term = match expr {
+            Expr::Int(v) => self.finish(ctx, Atom::Int(*v)),
+            Expr::Bool(v) => self.finish(ctx, Atom::Bool(*v)),
+            Expr::Variable(name) => match self.scope.iter().rposition(|(n, _)| n == name) {
+                Some(index) => {
+                    let fresh = self.scope[index].1;
+                    for (start, free) in self.funs.iter_mut().rev() {
+                        if *start <= index {
+                            break;
+                        }
+                        if !free.contains(&fresh) {
+                            free.push(fresh);
+                        }
+                    }
+                    self.finish(ctx, Atom::Name(fresh))
+                }
+                None => Term::Unbound(*name),
+            },
+            Expr::Program(prog, ret) => {
+                // The rest of the enclosing computation cannot see the lets.
+                let ctx = match ctx {
+                    Ctx::Bind(rest) => Ctx::Bind(Box::new(move |lower: &mut Lower, atom| {
+                        lower.scope.truncate(depth);
+                        rest(lower, atom)
+                    })),
+                    ctx => ctx,
+                };
+                self.statements(prog, ret, ctx)
+            }
+            Expr::Assign(name, _, exp) => self.assign(*name, exp, ctx),
+            Expr::BinOp(op, exp1, exp2) => self.term(
+                exp1,
+                Ctx::Bind(Box::new(move |lower, lhs| {
+                    lower.term(
+                        exp2,
+                        Ctx::Bind(Box::new(move |lower, rhs| {
+                            lower.let_prim(Prim::BinOp(*op, lhs, rhs), ctx)
+                        })),
+                    )
+                })),
+            ),
+            Expr::Compare(first, rest) => self.join(ctx, |lower, ctx| {
+                lower.term(
+                    first,
+                    Ctx::Bind(Box::new(move |lower, lhs| lower.links(lhs, rest, ctx))),
+                )
+            }),
+            Expr::UnaryOp(op, exp) => self.term(
+                exp,
+                Ctx::Bind(Box::new(move |lower, atom| {
+                    lower.let_prim(Prim::UnaryOp(*op, atom), ctx)
+                })),
+            ),
+            Expr::If(cond, exp1, exp2) => self.term(
+                cond,
+                Ctx::Bind(Box::new(move |lower, cond| {
+                    lower.join(ctx, |lower, ctx| {
+                        let then = lower.term(exp1, ctx.dup().unwrap());
+                        let otherwise = lower.term(exp2, ctx);
+                        Term::If(cond, Box::new(then), Box::new(otherwise))
+                    })
+                })),
+            ),
+            Expr::Lambda(param, _, body) => {
+                let fun = self.function(*param, body, None);
+                self.let_prim(Prim::Lambda(fun), ctx)
+            }
+            Expr::App(fun, arg) => self.term(
+                fun,
+                Ctx::Bind(Box::new(move |lower, fun| {
+                    lower.term(
+                        arg,
+                        Ctx::Bind(Box::new(move |lower, arg| match ctx {
+                            Ctx::Return => Term::TailCall(fun, arg),
+                            ctx => lower.let_prim(Prim::App(fun, arg), ctx),
+                        })),
+                    )
+                })),
+            ),
+            Expr::Compose(first, then) => self.term(
+                first,
+                Ctx::Bind(Box::new(move |lower, first| {
+                    lower.term(
+                        then,
+                        Ctx::Bind(Box::new(move |lower, then| {
+                            lower.let_prim(Prim::Compose(first, then), ctx)
+                        })),
+                    )
+                })),
+            ),
+        };
+        self.scope.truncate(depth);
+        term
+    }
+
+    /// Lowers a term that hands its value to `ctx` in more than one place,
+    /// through a continuation unless `ctx` is already one.
+    fn join<'a>(
+        &mut self,
+        ctx: Ctx<'a>,
+        branches: impl FnOnce(&mut Lower, Ctx<'static>) -> Term,
+    ) -> Term {
+        match ctx.dup() {
+            Some(ctx) => branches(self, ctx),
+            None => {
+                let (k, param) = (self.fresh(None), self.fresh(None));
+                let body = branches(self, Ctx::Jump(k));
+                let join = self.finish(ctx, Atom::Name(param));
+                Term::Join(k, param, Box::new(join), Box::new(body))
+            }
+        }
+    }
+
+    /// The links of a comparison chain from the one with `lhs` on its left.
+    fn links(&mut self, lhs: Atom, links: &[(BinOp, Expr)], ctx: Ctx<'static>) -> Term {
+        let (op, exp) = &links[0];
+        self.term(
+            exp,
+            Ctx::Bind(Box::new(move |lower, rhs| {
+                let test = lower.fresh(None);
+                let rest = if links.len() == 1 {
+                    lower.finish(ctx, Atom::Name(test))
+                } else {
+                    let more = lower.links(rhs, &links[1..], ctx.dup().unwrap());
+                    let fail = lower.finish(ctx, Atom::Bool(false));
+                    Term::If(Atom::Name(test), Box::new(more), Box::new(fail))
+                };
+                Term::Let(test, Prim::BinOp(*op, lhs, rhs), Box::new(rest))
+            })),
+        )
+    }
+
+    fn statements<'a>(&mut self, prog: &'a [Expr], ret: &'a Expr, ctx: Ctx<'a>) -> Term {
+        let Some((stmt, rest)) = prog.split_first() else {
+            return self.term(ret, ctx);
+        };
+        let ctx = Ctx::Bind(Box::new(move |lower: &mut Lower, _| {
+            lower.statements(rest, ret, ctx)
+        }));
+        match stmt {
+            Expr::Assign(name, _, exp) => self.assign(*name, exp, ctx),
+            stmt => self.term(stmt, ctx),
+        }
+    }
+
+    /// Binds `name`, which stays in scope for `ctx`.
+    fn assign<'a>(&mut self, name: Ident, exp: &'a Expr, ctx: Ctx<'a>) -> Term {
+        if let Expr::Lambda(param, _, body) = exp {
+            // A function may refer to itself.
+            let fresh = self.bind(name);
+            let fun = self.function(*param, body, Some(fresh));
+            let rest = self.finish(ctx, Atom::Name(fresh));
+            return Term::Let(fresh, Prim::Lambda(fun), Box::new(rest));
+        }
+        self.term(
+            exp,
+            Ctx::Bind(Box::new(move |lower, atom| {
+                let fresh = lower.bind(name);
+                let rest = lower.finish(ctx, Atom::Name(fresh));
+                Term::Let(fresh, Prim::Atom(atom), Box::new(rest))
+            })),
+        )
+    }
+
+    /// Lowers a function, bound to `own` if it can refer to itself.
+    fn function(&mut self, param: Ident, body: &Expr, own: Option<Name>) -> Rc<Fun> {
+        let depth = self.scope.len();
+        self.funs.push((depth, vec![]));
+        let param = self.bind(param);
+        let body = self.term(body, Ctx::Return);
+        self.scope.truncate(depth);
+        let (_, mut free) = self.funs.pop().unwrap();
+        free.retain(|name| Some(*name) != own);
+        Rc::new(Fun { param, free, body })
+    }
+}
+
+/// A value of the interpreter.
+#[derive(Debug, Clone)]
+pub enum IrValue {
+    Int(i64),
+    Bool(bool),
+    Closure(Rc<IrClosure>),
+    Compose(Box<IrValue>, Box<IrValue>),
+}
+
+#[derive(Debug)]
+pub struct IrClosure {
+    /// The name the function is bound to, through which it calls itself.
+    name: Name,
+    fun: Rc<Fun>,
+    env: HashMap<Name, IrValue>,
+}
+
+/// The outcome of running a function body: a value, or a call to make in
+/// its place.
+enum Next {
+    Return(IrValue),
+    Call(IrValue, IrValue),
+}
+
+/// Interprets a term, failing the way `Eval` does. Tail calls run in
+/// constant stack.
+pub fn eval(term: &Term) -> Result<IrValue> {
+    match run(term, HashMap::new())? {
+        Next::Return(val) => Ok(val),
+        Next::Call(fun, arg) => apply(fun, arg),
+    }
+}
+
+fn apply(mut fun: IrValue, mut arg: IrValue) -> Result<IrValue> {
+    loop {
+        let next = match fun {
+            IrValue::Closure(closure) => {
+                let mut env = closure.env.clone();
+                env.insert(closure.name, IrValue::Closure(Rc::clone(&closure)));
+                env.insert(closure.fun.param, arg);
+                run(&closure.fun.body, env)?
+            }
+            IrValue::Compose(first, then) => Next::Call(*then, apply(*first, arg)?),
+            _ => bail!("eval error: application to non-lambda!"),
+        };
+        match next {
+            Next::Return(val) => return Ok(val),
+            Next::Call(next_fun, next_arg) => (fun, arg) = (next_fun, next_arg),
+        }
+    }
+}
+
+fn run(mut term: &Term, mut env: HashMap<Name, IrValue>) -> Result<Next> {
+    let mut joins = HashMap::new();
+    loop {
+        let atom = |atom: &Atom| match atom {
+            Atom::Int(v) => IrValue::Int(*v),
+            Atom::Bool(v) => IrValue::Bool(*v),
+            Atom::Name(name) => env[name].clone(),
+        };
+        match term {
+            Term::Let(name, prim, body) => {
+                let val = match prim {
+                    Prim::Atom(a) => atom(a),
+                    Prim::BinOp(op, a, b) => {
+                        IrValue::from(op.apply(atom(a).scalar()?, atom(b).scalar()?)?)
+                    }
+                    Prim::UnaryOp(op, a) => IrValue::from(op.apply(atom(a).scalar()?)?),
+                    Prim::Lambda(fun) => IrValue::Closure(Rc::new(IrClosure {
+                        name: *name,
+                        fun: Rc::clone(fun),
+                        env: fun
+                            .free
+                            .iter()
+                            .map(|name| (*name, env[name].clone()))
+                            .collect(),
+                    })),
+                    Prim::App(fun, arg) => apply(atom(fun), atom(arg))?,
+                    Prim::Compose(first, then) => {
+                        IrValue::Compose(Box::new(atom(first)), Box::new(atom(then)))
+                    }
+                };
+                env.insert(*name, val);
+                term = body;
+            }
+            Term::Join(k, param, join, body) => {
+                joins.insert(*k, (*param, join.as_ref()));
+                term = body;
+            }
+            Term::Jump(k, arg) => {
+                let (param, join) = joins[k];
+                env.insert(param, atom(arg));
+                term = join;
+            }
+            Term::If(cond, then, otherwise) => match atom(cond) {
+                IrValue::Bool(true) => term = then,
+                IrValue::Bool(false) => term = otherwise,
+                _ => bail!("if expression: non-bool condition!"),
+            },
+            Term::Return(val) => return Ok(Next::Return(atom(val))),
+            Term::TailCall(fun, arg) => return Ok(Next::Call(atom(fun), atom(arg))),
+            Term::Unbound(_) => bail!("undefined variable"),
+        }
+    }
+}
+
+impl IrValue {
+    /// The int or bool operand of an operator.
+    fn scalar(&self) -> Result<Value> {
+        match self {
+            IrValue::Int(v) => Ok(Value::Int(*v)),
+            IrValue::Bool(v) => Ok(Value::Bool(*v)),
+            _ => bail!("invalid operand {self}"),
+        }
+    }
+}
+
+impl From<Value> for IrValue {
+    fn from(val: Value) -> Self {
+        match val {
+            Value::Int(v) => IrValue::Int(v),
+            Value::Bool(v) => IrValue::Bool(v),
+            _ => unreachable!("operators return ints and bools"),
+        }
+    }
+}
+
+impl fmt::Display for IrValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrValue::Int(v) => write!(f, "{v}"),
+            IrValue::Bool(v) => write!(f, "{v}"),
+            IrValue::Closure(closure) => match closure.fun.param.source {
+                Some(param) => write!(f, "lambda ({param})"),
+                None => write!(f, "lambda ({})", closure.fun.param),
+            },
+            IrValue::Compose(first, then) => write!(f, "{first} >> {then}"),
+        }
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            Some(name) => write!(f, "{name}.{}", self.id),
+            None => write!(f, "%{}", self.id),
+        }
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Atom::Int(v) => write!(f, "{v}"),
+            Atom::Bool(v) => write!(f, "{v}"),
+            Atom::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+impl Term {
+    fn write(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        let indent = "    ".repeat(depth);
+        match self {
+            Term::Let(name, prim, body) => {
+                write!(f, "{indent}let {name} = ")?;
+                match prim {
+                    Prim::Atom(a) => write!(f, "{a}")?,
+                    Prim::BinOp(op, a, b) => write!(f, "{a} {op} {b}")?,
+                    Prim::UnaryOp(op, a) => write!(f, "{op}{a}")?,
+                    Prim::Lambda(fun) => {
+                        writeln!(f, "lambda ({}) {{", fun.param)?;
+                        fun.body.write(f, depth + 1)?;
+                        write!(f, "{indent}}}")?;
+                    }
+                    Prim::App(fun, arg) => write!(f, "{fun}({arg})")?,
+                    Prim::Compose(first, then) => write!(f, "{first} >> {then}")?,
+                }
+                writeln!(f, ";")?;
+                body.write(f, depth)
+            }
+            Term::Join(k, param, join, body) => {
+                writeln!(f, "{indent}join {k}({param}) {{")?;
+                join.write(f, depth + 1)?;
+                writeln!(f, "{indent}}}")?;
+                body.write(f, depth)
+            }
+            Term::Jump(k, arg) => writeln!(f, "{indent}jump {k}({arg});"),
+            Term::If(cond, then, otherwise) => {
+                writeln!(f, "{indent}if {cond} {{")?;
+                then.write(f, depth + 1)?;
+                writeln!(f, "{indent}}} else {{")?;
+                otherwise.write(f, depth + 1)?;
+                writeln!(f, "{indent}}}")
+            }
+            Term::Return(val) => writeln!(f, "{indent}return {val};"),
+            Term::TailCall(fun, arg) => writeln!(f, "{indent}return {fun}({arg});"),
+            Term::Unbound(name) => writeln!(f, "{indent}fail undefined {name};"),
+        }
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        corpus::{FAILING, PROGRAMS},
+        desugar::parse_core,
+        eval::Eval,
+        optimize::optimize,
+    };
+
+    use super::{eval, IrValue, Lower};
+
+    #[test]
+    fn anf_lowering_prints_readably() {
+        let expr = parse_core(
+            "let f = lambda (n: int) { if (n < 1) { 0 } else { f(n - 1) } }; 1 + f(2) < 3 <= 4",
+        )
+        .unwrap();
+        assert_eq!(
+            Lower::lower(&expr).to_string(),
+            "\
+let f.0 = lambda (n.1) {
+    let %2 = n.1 < 1;
+    if %2 {
+        return 0;
+    } else {
+        let %3 = n.1 - 1;
+        return f.0(%3);
+    }
+};
+let %4 = f.0(2);
+let %5 = 1 + %4;
+let %6 = %5 < 3;
+if %6 {
+    let %7 = 3 <= 4;
+    return %7;
+} else {
+    return false;
+}
+"
+        );
+        let expr = parse_core("let x = if (true) { 1 } else { 2 }; x * 2").unwrap();
+        assert_eq!(
+            Lower::lower(&expr).to_string(),
+            "\
+join %0(%1) {
+    let x.2 = %1;
+    let %3 = x.2 * 2;
+    return %3;
+}
+if true {
+    jump %0(1);
+} else {
+    jump %0(2);
+}
+"
+        );
+    }
+
+    #[test]
+    fn anf_eval_matches_eval() {
+        let programs = PROGRAMS.iter().chain(FAILING).copied().chain([
+            "let sum = lambda (acc: int) { lambda (n: int) { if (n == 0) { acc } else { sum(acc + n)(n - 1) } } }; sum(0)(100000)",
+            "let g = lambda (u: int) { y }; let y = 1; g(0)",
+            "(+ 1) >> (* 2)",
+            "lambda (n: int) { n }",
+        ]);
+        for src in programs {
+            let expr = parse_core(src).unwrap();
+            let optimized = optimize(&expr);
+            let show = |res: anyhow::Result<String>| res.unwrap_or_else(|err| err.to_string());
+            assert_eq!(
+                show(eval(&Lower::lower(&expr)).map(|val| val.to_string())),
+                show(Eval::new().eval(&expr).map(|val| val.to_string())),
+                "{src}"
+            );
+            assert_eq!(
+                show(eval(&Lower::lower(&optimized)).map(|val| val.to_string())),
+                show(Eval::new().eval(&expr).map(|val| val.to_string())),
+                "{optimized}"
+            );
+        }
+    }
+
+    #[test]
+    fn anf_closures_capture_only_their_free_names() {
+        let expr = parse_core(
+            "let x = 1; let unused = 2; let f = lambda (y: int) { lambda (w: int) { x + y + f(w)(w) } }; f(3)",
+        )
+        .unwrap();
+        let IrValue::Closure(closure) = eval(&Lower::lower(&expr)).unwrap() else {
+            panic!("expected a closure")
+        };
+        let mut captured: Vec<_> = closure
+            .env
+            .keys()
+            .map(|name| name.source.unwrap().to_string())
+            .collect();
+        captured.sort();
+        assert_eq!(captured, ["f", "x", "y"]);
+    }
+}
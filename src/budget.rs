@@ -0,0 +1,231 @@
+//! Execution limits for untrusted scripts.
+//!
+//! A `Budget` is charged by the evaluators as they run. It counts evaluation
+//! steps and the depth of nested calls, and checks the clock and its
+//! cancellation token every so often, failing with `ResourceExhausted` once
+//! a limit is reached, or with `Cancelled`.
+
+use std::{
+    cell::Cell,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use wasm_bindgen::prelude::*;
+
+/// The clock and the cancellation token are checked once every this many
+/// steps.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// Limits on a single evaluation; `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The number of evaluation steps, roughly one per expression evaluated.
+    pub steps: Option<u64>,
+    /// The number of calls waiting on the calls they made. Tail calls do not
+    /// count.
+    pub call_depth: Option<usize>,
+    /// Wall-clock time from the start of the evaluation.
+    pub timeout: Option<Duration>,
+}
+
+/// The error an evaluation fails with when it runs out of a resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceExhausted {
+    Steps(u64),
+    CallDepth(usize),
+    Timeout(Duration),
+    /// The continuation stack of the stack-safe evaluator, in bytes.
+    Stack(usize),
+}
+
+impl fmt::Display for ResourceExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceExhausted::Steps(n) => write!(f, "step budget of {n} exhausted"),
+            ResourceExhausted::CallDepth(n) => write!(f, "call depth limit of {n} exceeded"),
+            ResourceExhausted::Timeout(t) => write!(f, "timed out after {t:?}"),
+            ResourceExhausted::Stack(n) => write!(f, "stack limit of {n} bytes exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for ResourceExhausted {}
+
+/// A flag that stops the evaluations watching it, shared between threads.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The error a cancelled evaluation fails with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "evaluation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Date, js_name = now)]
+    fn date_now() -> f64;
+}
+
+/// Time since the epoch; JavaScript's clock on the web, where the standard
+/// library has none.
+fn now() -> Duration {
+    if cfg!(target_arch = "wasm32") {
+        Duration::from_secs_f64(date_now() / 1000.0)
+    } else {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// The resources left to a running evaluation.
+#[derive(Debug)]
+pub struct Budget {
+    limits: Limits,
+    steps: Cell<u64>,
+    depth: Cell<usize>,
+    deadline: Option<Duration>,
+    cancel: Option<CancelToken>,
+}
+
+impl Budget {
+    /// Starts the clock on `limits`.
+    pub fn new(limits: Limits, cancel: Option<CancelToken>) -> Self {
+        Self {
+            limits,
+            steps: Cell::new(0),
+            depth: Cell::new(0),
+            deadline: limits.timeout.map(|timeout| now() + timeout),
+            cancel,
+        }
+    }
+
+    /// Charges one evaluation step.
+    pub fn step(&self) -> anyhow::Result<()> {
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if let Some(limit) = self.limits.steps {
+            if steps > limit {
+                return Err(ResourceExhausted::Steps(limit).into());
+            }
+        }
+        if steps.is_multiple_of(CHECK_INTERVAL) {
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    /// Fails if the deadline has passed or the evaluation was cancelled.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if let Some(deadline) = self.deadline {
+            if now() > deadline {
+                let timeout = self.limits.timeout.unwrap();
+                return Err(ResourceExhausted::Timeout(timeout).into());
+            }
+        }
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Enters a call that is not in tail position.
+    pub fn enter(&self) -> Result<(), ResourceExhausted> {
+        let depth = self.depth.get() + 1;
+        if let Some(limit) = self.limits.call_depth {
+            if depth > limit {
+                return Err(ResourceExhausted::CallDepth(limit));
+            }
+        }
+        self.depth.set(depth);
+        Ok(())
+    }
+
+    /// Returns from a call entered with `enter`.
+    pub fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new(Limits::default(), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Budget, CancelToken, Cancelled, Limits, ResourceExhausted};
+
+    #[test]
+    fn budget_limits() {
+        let budget = Budget::new(
+            Limits {
+                steps: Some(2),
+                call_depth: Some(1),
+                timeout: None,
+            },
+            None,
+        );
+        assert!(budget.step().is_ok());
+        assert!(budget.step().is_ok());
+        let err = budget.step().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ResourceExhausted::Steps(2)));
+        assert_eq!(budget.enter(), Ok(()));
+        assert_eq!(budget.enter(), Err(ResourceExhausted::CallDepth(1)));
+        budget.leave();
+        assert_eq!(budget.enter(), Ok(()));
+
+        let budget = Budget::new(
+            Limits {
+                timeout: Some(Duration::ZERO),
+                ..Limits::default()
+            },
+            None,
+        );
+        std::thread::sleep(Duration::from_millis(1));
+        let err = (0..2048).try_for_each(|_| budget.step()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ResourceExhausted::Timeout(Duration::ZERO))
+        );
+    }
+
+    #[test]
+    fn budget_cancellation() {
+        let token = CancelToken::new();
+        let budget = Budget::new(Limits::default(), Some(token.clone()));
+        assert!((0..2048).try_for_each(|_| budget.step()).is_ok());
+        std::thread::spawn(move || token.cancel()).join().unwrap();
+        let err = (0..2048).try_for_each(|_| budget.step()).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Cancelled));
+    }
+}
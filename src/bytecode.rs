@@ -0,0 +1,409 @@
+//! Compilation of resolved code to bytecode for the VM.
+//!
+//! Every function becomes a `Proto`: a flat list of stack machine
+//! instructions with its own constant pool. A function's parameter and
+//! `let`s live in the slots at the bottom of its frame on the VM stack, and
+//! its free variables are the upvalues captured by its closure, as worked
+//! out by the resolver.
+
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use crate::{
+    ident::Ident,
+    internal_value::Value,
+    operator::{BinOp, UnOp},
+    resolve::{Code, Function, Program, Var},
+    span::{Span, Spans},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Push a constant from the pool.
+    Const(u32),
+    Local(u32),
+    /// Store the top of the stack in a slot, leaving it on the stack.
+    SetLocal(u32),
+    /// Push an upvalue of the running closure.
+    Capture(u32),
+    /// Fail with an undefined variable.
+    Unbound,
+    Pop,
+    Swap,
+    BinOp(BinOp),
+    UnOp(UnOp),
+    /// Compare the two values on top of the stack; if the comparison holds
+    /// leave the right one for the next link of the chain, otherwise jump.
+    Compare(BinOp, u32),
+    Jump(u32),
+    /// Pop a condition and jump if it is false.
+    JumpIfFalse(u32),
+    /// Push a closure of the function with the index in the module.
+    Closure(u32),
+    Compose,
+    /// Call the function below the argument on top of the stack.
+    Call,
+    /// Call, replacing the frame of the running function.
+    TailCall,
+    Return,
+}
+
+/// A compiled function.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Proto {
+    pub param: Ident,
+    /// Where the upvalues of a new closure come from in the frame that
+    /// creates it.
+    pub captures: Vec<(Ident, Var)>,
+    /// The upvalue through which a function refers to itself.
+    pub recursive: Option<usize>,
+    pub frame_size: usize,
+    pub constants: Vec<Value>,
+    pub code: Vec<Op>,
+    /// Debug info: the source of the instructions from each pc on, sorted
+    /// by pc.
+    pub spans: Vec<(usize, Span)>,
+}
+
+impl Proto {
+    /// The source of the instruction at `pc`, if known.
+    pub fn span_at(&self, pc: usize) -> Option<Span> {
+        let after = self.spans.partition_point(|(start, _)| *start <= pc);
+        after.checked_sub(1).map(|i| self.spans[i].1)
+    }
+}
+
+/// A compiled program: its functions, and the index of the top level.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Module {
+    pub protos: Vec<Rc<Proto>>,
+    pub main: usize,
+}
+
+impl Module {
+    pub fn compile(program: &Program) -> Module {
+        Self::compile_with_spans(program, &Spans::default())
+    }
+
+    /// Compiles `program`, resolved from the core expression whose nodes
+    /// have `spans`, recording the source of every instruction.
+    pub fn compile_with_spans(program: &Program, spans: &Spans) -> Module {
+        let mut compiler = Compiler {
+            protos: vec![],
+            compiled: HashMap::new(),
+            spans,
+            next: 0,
+        };
+        let main = compiler.proto(
+            Ident::intern("main"),
+            vec![],
+            None,
+            program.frame_size,
+            &program.code,
+        );
+        Module {
+            protos: compiler.protos,
+            main,
+        }
+    }
+}
+
+struct Compiler<'s> {
+    protos: Vec<Rc<Proto>>,
+    /// The index of the proto of every function compiled so far, which may
+    /// be shared by several nodes of the code.
+    compiled: HashMap<*const Function, usize>,
+    spans: &'s Spans,
+    /// The node id of the next code to compile. Resolved code has a node
+    /// for every node of the core expression, in the same order.
+    next: usize,
+}
+
+/// The instructions of the function being compiled.
+struct Chunk {
+    constants: Vec<Value>,
+    code: Vec<Op>,
+    spans: Vec<(usize, Span)>,
+}
+
+impl Compiler<'_> {
+    fn proto(
+        &mut self,
+        param: Ident,
+        captures: Vec<(Ident, Var)>,
+        recursive: Option<usize>,
+        frame_size: usize,
+        body: &Code,
+    ) -> usize {
+        let mut chunk = Chunk {
+            constants: vec![],
+            code: vec![],
+            spans: vec![],
+        };
+        self.code(&mut chunk, body, true);
+        chunk.code.push(Op::Return);
+        self.protos.push(Rc::new(Proto {
+            param,
+            captures,
+            recursive,
+            frame_size,
+            constants: chunk.constants,
+            code: chunk.code,
+            spans: chunk.spans,
+        }));
+        self.protos.len() - 1
+    }
+
+    fn function(&mut self, fun: &Rc<Function>) -> usize {
+        if let Some(&index) = self.compiled.get(&Rc::as_ptr(fun)) {
+            self.next += nodes(&fun.body);
+            return index;
+        }
+        let index = self.proto(
+            fun.param,
+            fun.captures.clone(),
+            fun.recursive,
+            fun.frame_size,
+            &fun.body,
+        );
+        self.compiled.insert(Rc::as_ptr(fun), index);
+        index
+    }
+
+    /// Compiles `code` to leave its value on the stack. Calls in `tail`
+    /// position replace the running frame instead.
+    fn code(&mut self, chunk: &mut Chunk, code: &Code, tail: bool) {
+        let span = self.spans.get(self.next);
+        self.next += 1;
+        chunk.mark(span);
+        match code {
+            Code::Int(v) => chunk.constant(Value::Int(*v)),
+            Code::Bool(v) => chunk.constant(Value::Bool(*v)),
+            Code::Var(Var::Local(slot)) => chunk.code.push(Op::Local(*slot as u32)),
+            Code::Var(Var::Capture(i)) => chunk.code.push(Op::Capture(*i as u32)),
+            Code::Unbound(_) => chunk.code.push(Op::Unbound),
+            Code::Program(prog, ret) => {
+                for code in prog {
+                    self.child(chunk, span, code, false);
+                    chunk.code.push(Op::Pop);
+                }
+                self.child(chunk, span, ret, tail);
+            }
+            Code::Let(slot, code) => {
+                self.child(chunk, span, code, false);
+                chunk.code.push(Op::SetLocal(*slot as u32));
+            }
+            Code::BinOp(op, exp1, exp2) => {
+                self.child(chunk, span, exp1, false);
+                self.child(chunk, span, exp2, false);
+                chunk.code.push(Op::BinOp(*op));
+            }
+            Code::Compare(first, rest) => {
+                self.child(chunk, span, first, false);
+                let mut fails = vec![];
+                for (op, code) in rest {
+                    self.child(chunk, span, code, false);
+                    fails.push(chunk.code.len());
+                    chunk.code.push(Op::Compare(*op, 0));
+                }
+                chunk.code.push(Op::Pop);
+                chunk.constant(Value::Bool(true));
+                let end = chunk.code.len();
+                chunk.code.push(Op::Jump(0));
+                for fail in fails {
+                    chunk.patch(fail);
+                }
+                chunk.constant(Value::Bool(false));
+                chunk.patch(end);
+            }
+            Code::UnaryOp(op, exp1) => {
+                self.child(chunk, span, exp1, false);
+                chunk.code.push(Op::UnOp(*op));
+            }
+            Code::If(cond, exp1, exp2) => {
+                self.child(chunk, span, cond, false);
+                let otherwise = chunk.code.len();
+                chunk.code.push(Op::JumpIfFalse(0));
+                self.child(chunk, span, exp1, tail);
+                let end = chunk.code.len();
+                chunk.code.push(Op::Jump(0));
+                chunk.patch(otherwise);
+                self.child(chunk, span, exp2, tail);
+                chunk.patch(end);
+            }
+            Code::Lambda(fun) => {
+                let index = self.function(fun);
+                chunk.code.push(Op::Closure(index as u32));
+            }
+            Code::App(fun, var) => {
+                if let Code::Compose(first, then) = fun.as_ref() {
+                    // `(f >> g)(x)` is `g(f(x))`; no composed value is needed.
+                    // `g` goes below `f` and its argument, to be called with
+                    // the result.
+                    let compose = self.spans.get(self.next);
+                    self.next += 1;
+                    self.child(chunk, compose, first, false);
+                    self.child(chunk, compose, then, false);
+                    chunk.code.push(Op::Swap);
+                    chunk.mark(span);
+                    self.child(chunk, span, var, false);
+                    chunk.code.push(Op::Call);
+                } else {
+                    self.child(chunk, span, fun, false);
+                    self.child(chunk, span, var, false);
+                }
+                chunk.code.push(if tail { Op::TailCall } else { Op::Call });
+            }
+            Code::Compose(first, then) => {
+                self.child(chunk, span, first, false);
+                self.child(chunk, span, then, false);
+                chunk.code.push(Op::Compose);
+            }
+        }
+    }
+
+    /// Compiles `code`, a child of a node with `span`, which the
+    /// instructions following it belong to again.
+    fn child(&mut self, chunk: &mut Chunk, span: Option<Span>, code: &Code, tail: bool) {
+        self.code(chunk, code, tail);
+        chunk.mark(span);
+    }
+}
+
+/// The number of nodes in `code`.
+fn nodes(code: &Code) -> usize {
+    1 + match code {
+        Code::Int(_) | Code::Bool(_) | Code::Var(_) | Code::Unbound(_) => 0,
+        Code::Program(prog, ret) => prog.iter().map(|code| nodes(code)).sum::<usize>() + nodes(ret),
+        Code::Let(_, code) | Code::UnaryOp(_, code) => nodes(code),
+        Code::BinOp(_, exp1, exp2) | Code::App(exp1, exp2) | Code::Compose(exp1, exp2) => {
+            nodes(exp1) + nodes(exp2)
+        }
+        Code::Compare(first, rest) => {
+            nodes(first) + rest.iter().map(|(_, code)| nodes(code)).sum::<usize>()
+        }
+        Code::If(cond, exp1, exp2) => nodes(cond) + nodes(exp1) + nodes(exp2),
+        Code::Lambda(fun) => nodes(&fun.body),
+    }
+}
+
+impl Chunk {
+    fn constant(&mut self, val: Value) {
+        let index = match self.constants.iter().position(|c| *c == val) {
+            Some(index) => index,
+            None => {
+                self.constants.push(val);
+                self.constants.len() - 1
+            }
+        };
+        self.code.push(Op::Const(index as u32));
+    }
+
+    /// Records that the instructions from the end of the code on come from
+    /// `span`, if known.
+    fn mark(&mut self, span: Option<Span>) {
+        let Some(span) = span else {
+            return;
+        };
+        let pc = self.code.len();
+        match self.spans.last_mut() {
+            Some((last_pc, last)) if *last_pc == pc => *last = span,
+            Some((_, last)) if *last == span => {}
+            _ => self.spans.push((pc, span)),
+        }
+    }
+
+    /// Points the jump at `at` to the end of the code.
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len() as u32;
+        match &mut self.code[at] {
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::Compare(_, to) => *to = target,
+            op => unreachable!("patching {op:?}, which does not jump"),
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, proto) in self.protos.iter().enumerate() {
+            let name = if i == self.main {
+                "main".to_string()
+            } else {
+                format!("lambda ({})", proto.param)
+            };
+            writeln!(f, "{i}: {name}, {} slots", proto.frame_size)?;
+            for (pc, op) in proto.code.iter().enumerate() {
+                write!(f, "  {pc:4} {op:?}")?;
+                if let Op::Const(index) = op {
+                    write!(f, " ; {}", proto.constants[*index as usize])?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        desugar::parse_core,
+        resolve::{Code, Program, Resolver},
+    };
+
+    use super::{Module, Op};
+
+    #[test]
+    fn compile_closures_and_tail_calls() {
+        let expr = parse_core(
+            "let k = 2; let f = lambda (n: int) { if (n < 1) { k } else { f(n - 1) } }; f(3) + 1",
+        )
+        .unwrap();
+        let module = Module::compile(&Resolver::resolve(&expr));
+        assert_eq!(module.protos.len(), 2);
+        assert_eq!(module.main, 1);
+        let f = &module.protos[0];
+        assert_eq!(f.recursive, Some(1));
+        assert_eq!(f.constants.len(), 1);
+        assert_eq!(
+            f.code,
+            [
+                Op::Local(0),
+                Op::Const(0),
+                Op::BinOp(crate::operator::BinOp::Lt),
+                Op::JumpIfFalse(6),
+                Op::Capture(0),
+                Op::Jump(11),
+                Op::Capture(1),
+                Op::Local(0),
+                Op::Const(0),
+                Op::BinOp(crate::operator::BinOp::Sub),
+                Op::TailCall,
+                Op::Return,
+            ]
+        );
+        let main = &module.protos[1];
+        assert!(main.code.contains(&Op::Call));
+        assert!(!main.code.contains(&Op::TailCall));
+    }
+
+    #[test]
+    fn compile_shared_functions_once() {
+        let expr = parse_core("lambda (x: int) { x }").unwrap();
+        let resolved = Resolver::resolve(&expr);
+        let Code::Program(_, lambda) = resolved.code.as_ref() else {
+            panic!("{expr}");
+        };
+        let program = Program {
+            frame_size: 0,
+            code: Rc::new(Code::Compose(Rc::clone(lambda), Rc::clone(lambda))),
+        };
+        let module = Module::compile(&program);
+        assert_eq!(module.protos.len(), 2);
+        assert_eq!(
+            module.protos[module.main].code,
+            [Op::Closure(0), Op::Closure(0), Op::Compose, Op::Return]
+        );
+    }
+}
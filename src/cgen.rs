@@ -0,0 +1,488 @@
+//! Compilation of type-checked expressions to C.
+//!
+//! The output is a single C99 file with a small runtime ahead of the
+//! program. As for WebAssembly, the resolver does the closure conversion:
+//! every function becomes a C function taking its closure and its argument.
+//! Values are boxed in a tagged `rs_value`, so the program can print its
+//! result the way `Eval` displays it. Every intermediate value gets a
+//! temporary of its own, which keeps the order of evaluation explicit.
+//! Closures are never freed. A call of a function to itself in tail position
+//! becomes a jump back to the top of the function; other calls use the C
+//! stack, so other deep tail recursion needs a C compiler that turns tail
+//! calls into jumps, as gcc and clang do at `-O2`.
+
+use std::fmt::Write;
+
+use crate::{
+    expression::Expr,
+    operator::{BinOp, UnOp},
+    resolve::{Code, Function, Resolver, Var},
+};
+
+const RUNTIME: &str = r#"/*
+ * Generated by rscript. Calls of a function to itself in tail position run
+ * in constant stack space; other tail calls only do if the C compiler
+ * optimises them, so compile with -O2 for deep mutual recursion.
+ */
+
+#include <inttypes.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+
+typedef struct rs_closure rs_closure;
+
+typedef struct {
+    enum { RS_INT, RS_BOOL, RS_LAMBDA } tag;
+    union {
+        int64_t i;
+        int b;
+        rs_closure *f;
+    } as;
+} rs_value;
+
+struct rs_closure {
+    rs_value (*code)(rs_closure *env, rs_value arg);
+    /* The parameter's name, for printing. */
+    const char *param;
+    rs_value captures[];
+};
+
+/* Runtime functions are inline so that unused ones draw no warnings. */
+static inline void rs_fail(const char *msg) {
+    fprintf(stderr, "error: %s\n", msg);
+    exit(1);
+}
+
+static inline rs_value rs_int(int64_t i) {
+    rs_value v;
+    v.tag = RS_INT;
+    v.as.i = i;
+    return v;
+}
+
+static inline rs_value rs_bool(int b) {
+    rs_value v;
+    v.tag = RS_BOOL;
+    v.as.b = b;
+    return v;
+}
+
+static inline rs_value rs_closure_new(rs_value (*code)(rs_closure *, rs_value),
+                                      const char *param, size_t captures) {
+    rs_closure *f = malloc(sizeof(rs_closure) + captures * sizeof(rs_value));
+    if (!f) rs_fail("out of memory");
+    f->code = code;
+    f->param = param;
+    rs_value v;
+    v.tag = RS_LAMBDA;
+    v.as.f = f;
+    return v;
+}
+
+static inline rs_value rs_apply(rs_value f, rs_value arg) {
+    if (f.tag != RS_LAMBDA) rs_fail("eval error: application to non-lambda!");
+    return f.as.f->code(f.as.f, arg);
+}
+
+/* `(f >> g)(x)`, with `f` and `g` captured. */
+static inline rs_value rs_compose(rs_closure *env, rs_value arg) {
+    return rs_apply(env->captures[1], rs_apply(env->captures[0], arg));
+}
+
+/* Arithmetic wraps on overflow, through unsigned ints. */
+static inline rs_value rs_add(rs_value a, rs_value b) {
+    return rs_int((int64_t)((uint64_t)a.as.i + (uint64_t)b.as.i));
+}
+
+static inline rs_value rs_sub(rs_value a, rs_value b) {
+    return rs_int((int64_t)((uint64_t)a.as.i - (uint64_t)b.as.i));
+}
+
+static inline rs_value rs_mul(rs_value a, rs_value b) {
+    return rs_int((int64_t)((uint64_t)a.as.i * (uint64_t)b.as.i));
+}
+
+static inline rs_value rs_div(rs_value a, rs_value b) {
+    if (b.as.i == 0) rs_fail("division by zero");
+    if (b.as.i == -1) return rs_sub(rs_int(0), a);
+    return rs_int(a.as.i / b.as.i);
+}
+
+static inline void rs_print(rs_value v) {
+    switch (v.tag) {
+    case RS_INT:
+        printf("%" PRId64, v.as.i);
+        break;
+    case RS_BOOL:
+        printf(v.as.b ? "true" : "false");
+        break;
+    case RS_LAMBDA:
+        if (v.as.f->code == rs_compose) {
+            rs_print(v.as.f->captures[0]);
+            printf(" >> ");
+            rs_print(v.as.f->captures[1]);
+        } else {
+            printf("lambda (%s)", v.as.f->param);
+        }
+        break;
+    }
+}
+"#;
+
+pub struct CGen {
+    /// The compiled functions, each preceded by its declaration.
+    funs: Vec<String>,
+}
+
+/// The body of the function being compiled.
+struct Body {
+    code: String,
+    depth: usize,
+    temps: usize,
+    /// The capture through which the function refers to itself.
+    recursive: Option<usize>,
+    /// Whether the body jumps back to its top for a call to itself.
+    loops: bool,
+}
+
+impl CGen {
+    /// Compiles a type-checked program to a C program printing its value.
+    pub fn compile(expr: &Expr) -> String {
+        let program = Resolver::resolve(expr);
+        let mut gen = CGen { funs: vec![] };
+        let mut body = Body::new();
+        let val = gen.code(&mut body, &program.code);
+
+        let mut out = String::from(RUNTIME);
+        for i in 0..gen.funs.len() {
+            write!(out, "\nstatic rs_value f{i}(rs_closure *env, rs_value s0);").unwrap();
+        }
+        out.push('\n');
+        for fun in &gen.funs {
+            out.push('\n');
+            out.push_str(fun);
+        }
+        out.push_str("\nint main(void) {\n");
+        out.push_str(&slots(0, program.frame_size));
+        out.push_str(&body.code);
+        writeln!(
+            out,
+            "    rs_print({val});\n    printf(\"\\n\");\n    return 0;\n}}"
+        )
+        .unwrap();
+        out
+    }
+
+    /// Compiles a function, returning its name.
+    fn function(&mut self, fun: &Function) -> String {
+        let index = self.funs.len();
+        // Functions nested in the body are numbered after this one.
+        self.funs.push(String::new());
+        let mut body = Body::new();
+        body.recursive = fun.recursive;
+        self.ret(&mut body, &fun.body);
+        let mut out = format!("/* lambda ({}) */\n", fun.param);
+        writeln!(
+            out,
+            "static rs_value f{index}(rs_closure *env, rs_value s0) {{"
+        )
+        .unwrap();
+        // Unused in functions without captures.
+        out.push_str("    (void)env;\n");
+        out.push_str(&slots(1, fun.frame_size));
+        if body.loops {
+            out.push_str("tail:;\n");
+        }
+        out.push_str(&body.code);
+        out.push_str("}\n");
+        self.funs[index] = out;
+        format!("f{index}")
+    }
+
+    /// Compiles `code` in tail position, returning its value.
+    fn ret(&mut self, body: &mut Body, code: &Code) {
+        match code {
+            Code::Program(prog, ret) => {
+                for code in prog {
+                    self.code(body, code);
+                }
+                self.ret(body, ret);
+            }
+            Code::If(cond, exp1, exp2) => {
+                let cond = self.code(body, cond);
+                body.open(format!("if ({cond}.as.b) {{"));
+                self.ret(body, exp1);
+                body.reopen("} else {");
+                self.ret(body, exp2);
+                body.close();
+            }
+            // A call of the function to itself: its closure stays the same,
+            // so only the parameter changes.
+            Code::App(fun, arg) if body.is_itself(fun) => {
+                let arg = self.code(body, arg);
+                body.emit(format!("s0 = {arg};"));
+                body.emit("goto tail;");
+                body.loops = true;
+            }
+            // A call the C compiler can turn into a jump.
+            Code::App(fun, arg) => {
+                let fun = self.code(body, fun);
+                let arg = self.code(body, arg);
+                body.emit(format!("return rs_apply({fun}, {arg});"));
+            }
+            code => {
+                let val = self.code(body, code);
+                body.emit(format!("return {val};"));
+            }
+        }
+    }
+
+    /// Compiles `code`, returning a variable or literal holding its value.
+    fn code(&mut self, body: &mut Body, code: &Code) -> String {
+        match code {
+            Code::Int(v) if *v == i64::MIN => "rs_int(INT64_MIN)".to_string(),
+            Code::Int(v) => format!("rs_int({v})"),
+            Code::Bool(v) => format!("rs_bool({})", *v as i64),
+            Code::Var(Var::Local(slot)) => format!("s{slot}"),
+            Code::Var(Var::Capture(i)) => format!("env->captures[{i}]"),
+            Code::Unbound(name) => {
+                body.emit(format!("rs_fail(\"undefined variable\"); /* {name} */"));
+                "rs_int(0)".to_string()
+            }
+            Code::Program(prog, ret) => {
+                for code in prog {
+                    self.code(body, code);
+                }
+                self.code(body, ret)
+            }
+            Code::Let(slot, code) => {
+                let val = self.code(body, code);
+                body.emit(format!("s{slot} = {val};"));
+                format!("s{slot}")
+            }
+            Code::BinOp(op, exp1, exp2) => {
+                let lhs = self.code(body, exp1);
+                let rhs = self.code(body, exp2);
+                let val = match op {
+                    BinOp::Add => format!("rs_add({lhs}, {rhs})"),
+                    BinOp::Sub => format!("rs_sub({lhs}, {rhs})"),
+                    BinOp::Mul => format!("rs_mul({lhs}, {rhs})"),
+                    BinOp::Div => format!("rs_div({lhs}, {rhs})"),
+                    BinOp::And => format!("rs_bool({lhs}.as.b && {rhs}.as.b)"),
+                    BinOp::Or => format!("rs_bool({lhs}.as.b || {rhs}.as.b)"),
+                    op => format!("rs_bool({})", comparison(*op, &lhs, &rhs)),
+                };
+                body.temp(val)
+            }
+            Code::Compare(first, rest) => {
+                let result = body.temp("rs_bool(0)".to_string());
+                let mut lhs = self.code(body, first);
+                let depth = body.depth;
+                for (op, code) in rest {
+                    let rhs = self.code(body, code);
+                    body.open(format!("if ({}) {{", comparison(*op, &lhs, &rhs)));
+                    lhs = rhs;
+                }
+                body.emit(format!("{result} = rs_bool(1);"));
+                while body.depth > depth {
+                    body.close();
+                }
+                result
+            }
+            Code::UnaryOp(UnOp::Neg, exp1) => {
+                let val = self.code(body, exp1);
+                body.temp(format!("rs_sub(rs_int(0), {val})"))
+            }
+            Code::UnaryOp(UnOp::Not, exp1) => {
+                let val = self.code(body, exp1);
+                body.temp(format!("rs_bool(!{val}.as.b)"))
+            }
+            Code::If(cond, exp1, exp2) => {
+                let cond = self.code(body, cond);
+                let result = body.declare();
+                body.open(format!("if ({cond}.as.b) {{"));
+                let val = self.code(body, exp1);
+                body.emit(format!("{result} = {val};"));
+                body.reopen("} else {");
+                let val = self.code(body, exp2);
+                body.emit(format!("{result} = {val};"));
+                body.close();
+                result
+            }
+            Code::Lambda(fun) => {
+                let name = self.function(fun);
+                let captures = fun.captures.len();
+                let closure = body.temp(format!(
+                    "rs_closure_new({name}, \"{}\", {captures})",
+                    fun.param
+                ));
+                for (i, (_, var)) in fun.captures.iter().enumerate() {
+                    let val = match fun.recursive {
+                        Some(rec) if rec == i => closure.clone(),
+                        _ => self.code(body, &Code::Var(*var)),
+                    };
+                    body.emit(format!("{closure}.as.f->captures[{i}] = {val};"));
+                }
+                closure
+            }
+            Code::App(fun, arg) => {
+                let fun = self.code(body, fun);
+                let arg = self.code(body, arg);
+                body.temp(format!("rs_apply({fun}, {arg})"))
+            }
+            Code::Compose(first, then) => {
+                let first = self.code(body, first);
+                let then = self.code(body, then);
+                let closure = body.temp("rs_closure_new(rs_compose, \"x\", 2)".to_string());
+                body.emit(format!("{closure}.as.f->captures[0] = {first};"));
+                body.emit(format!("{closure}.as.f->captures[1] = {then};"));
+                closure
+            }
+        }
+    }
+}
+
+impl Body {
+    fn new() -> Body {
+        Body {
+            code: String::new(),
+            depth: 1,
+            temps: 0,
+            recursive: None,
+            loops: false,
+        }
+    }
+
+    /// Whether `fun` is the function being compiled.
+    fn is_itself(&self, fun: &Code) -> bool {
+        matches!(fun, Code::Var(Var::Capture(i)) if self.recursive == Some(*i))
+    }
+
+    fn emit(&mut self, line: impl AsRef<str>) {
+        writeln!(self.code, "{}{}", "    ".repeat(self.depth), line.as_ref()).unwrap();
+    }
+
+    fn open(&mut self, line: impl AsRef<str>) {
+        self.emit(line);
+        self.depth += 1;
+    }
+
+    fn reopen(&mut self, line: &str) {
+        self.depth -= 1;
+        self.open(line);
+    }
+
+    fn close(&mut self) {
+        self.depth -= 1;
+        self.emit("}");
+    }
+
+    /// Declares a new temporary, to be assigned in nested blocks.
+    fn declare(&mut self) -> String {
+        let temp = format!("t{}", self.temps);
+        self.temps += 1;
+        self.emit(format!("rs_value {temp};"));
+        temp
+    }
+
+    /// Stores `val` in a new temporary.
+    fn temp(&mut self, val: String) -> String {
+        let temp = format!("t{}", self.temps);
+        self.temps += 1;
+        self.emit(format!("rs_value {temp} = {val};"));
+        temp
+    }
+}
+
+/// Declares the slots from `first` on, as used so that a `let` nothing
+/// reads compiles with -Wall -Werror.
+fn slots(first: usize, frame_size: usize) -> String {
+    (first..frame_size)
+        .map(|slot| format!("    rs_value s{slot} = {{RS_INT, {{0}}}};\n    (void)s{slot};\n"))
+        .collect()
+}
+
+fn comparison(op: BinOp, lhs: &str, rhs: &str) -> String {
+    let op = match op {
+        BinOp::Eq => "==",
+        BinOp::Ne => "!=",
+        op => op.symbol(),
+    };
+    format!("{lhs}.as.i {op} {rhs}.as.i")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use crate::{
+        corpus::{found, FAILING, PROGRAMS},
+        desugar::parse_core,
+        eval::Eval,
+        expression::Expr,
+        optimize::optimize,
+    };
+
+    use super::CGen;
+
+    /// Compiles and runs the program, returning what it prints.
+    fn run_c(name: &str, expr: &Expr) -> String {
+        let c = CGen::compile(expr);
+        let dir = std::env::temp_dir().join(format!("rscript-cgen-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (file, exe) = (dir.join(format!("{name}.c")), dir.join(name));
+        fs::write(&file, &c).unwrap();
+        let status = Command::new("cc")
+            // Unoptimised, so that the C compiler eliminates no tail calls.
+            .args(["-std=c99", "-Wall", "-Werror", "-o"])
+            .args([&exe, &file])
+            .status()
+            .expect("cannot run cc");
+        assert!(status.success(), "{expr}\n{c}");
+        let output = Command::new(&exe).output().unwrap();
+        let out = if output.status.success() {
+            output.stdout
+        } else {
+            output.stderr
+        };
+        String::from_utf8(out).unwrap().trim().to_string()
+    }
+
+    /// What the compiled program should print.
+    fn eval(expr: &Expr) -> String {
+        match Eval::new().eval(expr) {
+            Ok(val) => val.to_string(),
+            Err(err) => format!("error: {err}"),
+        }
+    }
+
+    #[test]
+    fn cgen_matches_eval() {
+        if !found("cc") {
+            return;
+        }
+        let programs = PROGRAMS.iter().chain(FAILING).copied().chain([
+            "let sum = lambda (acc: int) { lambda (n: int) { if (n == 0) { acc } else { sum(acc + n)(n - 1) } } }; sum(0)(10000)",
+            "let loop = lambda (n: int) { if (n == 0) { 0 } else { loop(n - 1) } }; loop(10000000)",
+            "lambda (n: int) { n }",
+            "(+ 1) >> (* 2)",
+        ]);
+        for (i, src) in programs.enumerate() {
+            let expr = parse_core(src).unwrap();
+            assert_eq!(run_c(&format!("case{i}"), &expr), eval(&expr), "{src}");
+            // Folding leaves literals C cannot write, such as i64::MIN.
+            let optimized = optimize(&expr);
+            let out = run_c(&format!("case{i}_optimized"), &optimized);
+            assert_eq!(out, eval(&expr), "{optimized}");
+        }
+    }
+
+    #[test]
+    fn cgen_writes_the_smallest_int() {
+        let expr = optimize(&parse_core("let min = 0 - 9223372036854775807 - 1; min").unwrap());
+        assert_eq!(expr, Expr::Int(i64::MIN));
+        assert!(CGen::compile(&expr).contains("rs_int(INT64_MIN)"));
+    }
+}
@@ -0,0 +1,69 @@
+//! Programs shared by the tests of every backend, which must all give the
+//! same result as `Eval`. Each backend adds cases of its own for what is
+//! particular to it.
+
+/// Type-checked programs whose value is an int or a bool.
+pub const PROGRAMS: &[&str] = &[
+    "1 + 2 * 3 - -4 / 2",
+    "9223372036854775807 + 1",
+    "let min = 0 - 9223372036854775807 - 1; min / -1",
+    "let x = 5; 0 <= x - 5 < 10",
+    "1 < 2 <= 2 < 3",
+    "3 > 2 > 2",
+    "if (!(1 == 2) && true) { 1 } else { 2 }",
+    "let b = 1 < 2 < 3 && true; if (b) { 1 } else { 2 }",
+    "let x = 1; let x = x + 1; let x = x * 10; x",
+    "let x = 1; let y = if (x < 2) { (lambda (u: int) { let x = 10; x + u })(x) } else { 20 }; y + x",
+    "let inc = lambda (x: int) { x + 1 }; let dbl = lambda (x: int) { x * 2 }; (inc << dbl)(3) + (3 |> inc >> dbl)",
+    "let both = (+ 1) >> (* 2); both(both(0))",
+    "let fold = lambda (f) { lambda (z) { f(f(z)(1))(2) } }; fold((+))(0)",
+    "let x = 1; let g = lambda (u: int) { x }; let x = 2; g(0) * 10 + x",
+    "let mk = lambda (n: int) { lambda (u: int) { n } }; let a = mk(1); let b = mk(2); a(0) * 10 + b(0)",
+    "let twice = lambda (f) { lambda (x: int) { f(f(x)) } }; let x = 3; twice(lambda (y: int) { y * x })(x)",
+    "let apply = lambda (x: int) { lambda (f) { f(x) } }; let x = 5; apply(x + 1)(lambda (y: int) { x * y })",
+    "let f = lambda (n: int) { if (n < 2) { n } else { f(n - 1) + f(n - 2) } }; f(15)",
+    "let f = lambda (n: int) { let m = n - 1; if (n < 2) { n } else { f(m) + f(m - 1) } }; f(15)",
+    "let new = 3; let f = lambda (var: int) { new + var }; f(4)",
+    "let sub = lambda (a: int) { lambda (b: int) { a - b } }; infixl 6 <-> = sub; let sub = 0; 10 <-> 3 <-> sub",
+];
+
+/// Whether the tool a backend's tests run their output with is installed.
+/// Without it they are skipped, saying so, rather than failed.
+pub fn found(tool: &str) -> bool {
+    let found = std::process::Command::new(tool)
+        .arg("--version")
+        .output()
+        .is_ok();
+    if !found {
+        eprintln!("skipping: {tool} not found");
+    }
+    found
+}
+
+/// Type-checked programs that fail at runtime.
+pub const FAILING: &[&str] = &[
+    "let zero = 0; 1 / zero",
+    "let zero = 0; let f = lambda (b: bool) { b }; f(false && 1 / zero == 1)",
+    "let zero = 0; let f = lambda (x: int) { 1 }; f(1 / zero)",
+    "let zero = 0; let id = lambda (x: int) { x }; let unused = id(1 / zero); 2",
+];
+
+mod tests {
+    use crate::{desugar::parse_core, eval::Eval, internal_value::Value, types::TypeInfer};
+
+    use super::{FAILING, PROGRAMS};
+
+    #[test]
+    fn corpus_type_checks_and_runs() {
+        for src in PROGRAMS.iter().chain(FAILING) {
+            let expr = parse_core(src).unwrap();
+            TypeInfer::new().infer_type(&expr).unwrap();
+            let val = Eval::new().eval(&expr);
+            match val {
+                Ok(Value::Int(_) | Value::Bool(_)) => assert!(PROGRAMS.contains(src), "{src}"),
+                Err(_) => assert!(FAILING.contains(src), "{src}"),
+                val => panic!("{src}: {val:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Desugaring: turns the surface syntax into the core `Expr` that the type
+//! checker and the backends work on. Pipes, flipped composition, user
+//! operators and sections all become plain calls and lambdas.
+//!
+//! The span of every core node is recorded before its children are
+//! desugared, so the table of spans is indexed by node id. A node made up
+//! by the desugaring gets the span of the syntax it came from.
+
+use anyhow::Result;
+
+use crate::{
+    expression::Expr,
+    ident::Ident,
+    parse::Parser,
+    span::{Span, Spans},
+    syntax::{Operator, Syntax, SyntaxKind},
+};
+
+/// Parameter names of the lambdas built for operator sections. They are not
+/// valid identifiers, so they never capture a variable of the script.
+const LHS: &str = "$lhs";
+const RHS: &str = "$rhs";
+
+/// Returns the core of `syntax`, with the span of each of its nodes.
+pub fn desugar(syntax: &Syntax) -> (Expr, Spans) {
+    let mut desugar = Desugar {
+        spans: Spans::default(),
+    };
+    let expr = desugar.expr(syntax);
+    (expr, desugar.spans)
+}
+
+/// Parses a program and desugars it to the core, for callers that do not
+/// need the spans.
+pub fn parse_core(src: &str) -> Result<Expr> {
+    Ok(desugar(&Parser::new(src).program()?).0)
+}
+
+/// An operand of a desugared operator.
+enum Operand<'a> {
+    Syntax(&'a Syntax),
+    /// The parameter of a section that stands for a missing operand.
+    Param(Ident),
+}
+
+struct Desugar {
+    spans: Spans,
+}
+
+impl Desugar {
+    /// Records the span of the next core node.
+    fn at(&mut self, span: Span) {
+        self.spans.push(span);
+    }
+
+    fn expr(&mut self, syntax: &Syntax) -> Expr {
+        let span = syntax.span;
+        match &syntax.kind {
+            SyntaxKind::Int(v) => {
+                self.at(span);
+                Expr::int(*v)
+            }
+            SyntaxKind::Bool(v) => {
+                self.at(span);
+                Expr::boolean(*v)
+            }
+            SyntaxKind::Variable(name) => {
+                self.at(span);
+                Expr::variable(*name)
+            }
+            SyntaxKind::Block(stmts, ret) => {
+                self.at(span);
+                let prog = stmts.iter().map(|stmt| self.expr(stmt)).collect();
+                Expr::program(prog, self.expr(ret))
+            }
+            SyntaxKind::Let(name, ty, exp) => {
+                self.at(span);
+                Expr::assign(*name, ty.clone(), self.expr(exp))
+            }
+            SyntaxKind::Binary(op, lhs, rhs) => {
+                self.operator(*op, span, Operand::Syntax(lhs), Operand::Syntax(rhs))
+            }
+            SyntaxKind::Compare(first, rest) => {
+                self.at(span);
+                let first = self.expr(first);
+                let rest = rest.iter().map(|(op, exp)| (*op, self.expr(exp))).collect();
+                Expr::compare(first, rest)
+            }
+            SyntaxKind::Unary(op, exp) => {
+                self.at(span);
+                Expr::unaryop(*op, self.expr(exp))
+            }
+            SyntaxKind::If(cond, exp1, exp2) => {
+                self.at(span);
+                let cond = self.expr(cond);
+                let exp1 = self.expr(exp1);
+                Expr::if_expr(cond, exp1, self.expr(exp2))
+            }
+            SyntaxKind::Lambda(param, ty, body) => {
+                self.at(span);
+                Expr::lambda(*param, ty.clone(), self.expr(body))
+            }
+            SyntaxKind::App(fun, arg) => {
+                self.at(span);
+                let fun = self.expr(fun);
+                Expr::app(fun, self.expr(arg))
+            }
+            SyntaxKind::Section(op, lhs, rhs) => self.section(*op, span, lhs, rhs),
+        }
+    }
+
+    fn operand(&mut self, operand: Operand, span: Span) -> Expr {
+        match operand {
+            Operand::Syntax(syntax) => self.expr(syntax),
+            Operand::Param(name) => {
+                self.at(span);
+                Expr::variable(name)
+            }
+        }
+    }
+
+    fn operator(&mut self, op: Operator, span: Span, lhs: Operand, rhs: Operand) -> Expr {
+        self.at(span);
+        match op {
+            Operator::Builtin(op) => {
+                let lhs = self.operand(lhs, span);
+                Expr::binop(op, lhs, self.operand(rhs, span))
+            }
+            Operator::Pipe => {
+                let fun = self.operand(rhs, span);
+                Expr::app(fun, self.operand(lhs, span))
+            }
+            Operator::Compose { flipped } => {
+                let (first, then) = if flipped { (rhs, lhs) } else { (lhs, rhs) };
+                let first = self.operand(first, span);
+                Expr::compose(first, self.operand(then, span))
+            }
+            Operator::User(fun) => {
+                self.at(span);
+                self.at(span);
+                let lhs = self.operand(lhs, span);
+                let call = Expr::app(Expr::variable(fun), lhs);
+                Expr::app(call, self.operand(rhs, span))
+            }
+        }
+    }
+
+    /// `(+)` becomes `lambda ($lhs) { lambda ($rhs) { $lhs + $rhs } }`,
+    /// and a section with one operand a lambda of the other. An operand
+    /// that takes work to evaluate is bound once, outside the lambda:
+    /// `(f(x) -)` becomes `{ let $lhs = f(x); lambda ($rhs) { $lhs - $rhs } }`.
+    fn section(
+        &mut self,
+        op: Operator,
+        span: Span,
+        lhs: &Option<Box<Syntax>>,
+        rhs: &Option<Box<Syntax>>,
+    ) -> Expr {
+        let (lhs_param, rhs_param) = (Ident::intern(LHS), Ident::intern(RHS));
+        match (lhs, rhs) {
+            (None, None) => {
+                self.at(span);
+                self.at(span);
+                let body = self.operator(
+                    op,
+                    span,
+                    Operand::Param(lhs_param),
+                    Operand::Param(rhs_param),
+                );
+                Expr::lambda(lhs_param, None, Expr::lambda(rhs_param, None, body))
+            }
+            (None, Some(rhs)) if is_simple(rhs) => {
+                self.at(span);
+                let body = self.operator(op, span, Operand::Param(lhs_param), Operand::Syntax(rhs));
+                Expr::lambda(lhs_param, None, body)
+            }
+            (None, Some(rhs)) => {
+                let value = self.bound_operand(span, rhs_param, rhs);
+                self.at(span);
+                let body = self.operator(
+                    op,
+                    span,
+                    Operand::Param(lhs_param),
+                    Operand::Param(rhs_param),
+                );
+                Expr::program(vec![value], Expr::lambda(lhs_param, None, body))
+            }
+            (Some(lhs), None) if is_simple(lhs) => {
+                self.at(span);
+                let body = self.operator(op, span, Operand::Syntax(lhs), Operand::Param(rhs_param));
+                Expr::lambda(rhs_param, None, body)
+            }
+            (Some(lhs), None) => {
+                let value = self.bound_operand(span, lhs_param, lhs);
+                self.at(span);
+                let body = self.operator(
+                    op,
+                    span,
+                    Operand::Param(lhs_param),
+                    Operand::Param(rhs_param),
+                );
+                Expr::program(vec![value], Expr::lambda(rhs_param, None, body))
+            }
+            (Some(lhs), Some(rhs)) => {
+                self.operator(op, span, Operand::Syntax(lhs), Operand::Syntax(rhs))
+            }
+        }
+    }
+
+    /// The `let` binding the operand of a section to `name`, inside the
+    /// block whose node is recorded first.
+    fn bound_operand(&mut self, span: Span, name: Ident, operand: &Syntax) -> Expr {
+        self.at(span);
+        self.at(span);
+        Expr::assign(name, None, self.expr(operand))
+    }
+}
+
+/// Whether evaluating `syntax` is as cheap as looking up a bound copy of it.
+fn is_simple(syntax: &Syntax) -> bool {
+    matches!(
+        syntax.kind,
+        SyntaxKind::Int(_) | SyntaxKind::Bool(_) | SyntaxKind::Variable(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::Parser;
+
+    use super::desugar;
+
+    #[test]
+    fn desugar_keeps_spans_in_pre_order() {
+        let src = "infixl 6 <+> = plus; 1 <+> 2 |> (* 3)";
+        let (expr, spans) = desugar(&Parser::new(src).program().unwrap());
+        assert_eq!(
+            expr.to_string(),
+            "let $op3c2b3e: ? = plus; lambda ($lhs:?) { ($lhs * Int(3)) }($op3c2b3e(Int(1))(Int(2)))"
+        );
+        let at: Vec<_> = (0..spans.len())
+            .map(|id| {
+                let span = spans.get(id).unwrap();
+                &src[span.start..span.end]
+            })
+            .collect();
+        assert_eq!(
+            at,
+            [
+                src,
+                "infixl 6 <+> = plus;",
+                "plus",
+                "1 <+> 2 |> (* 3)",
+                "(* 3)",
+                "(* 3)",
+                "(* 3)",
+                "3",
+                "1 <+> 2",
+                "1 <+> 2",
+                "1 <+> 2",
+                "1",
+                "2",
+            ]
+        );
+    }
+
+    #[test]
+    fn desugar_binds_section_operands_once() {
+        let src = "(f(1) -)";
+        let (expr, spans) = desugar(&Parser::new(src).program().unwrap());
+        assert_eq!(
+            expr.to_string(),
+            " let $lhs: ? = f(Int(1)); lambda ($rhs:?) { ($lhs - $rhs) }"
+        );
+        let at: Vec<_> = (0..spans.len())
+            .map(|id| {
+                let span = spans.get(id).unwrap();
+                &src[span.start..span.end]
+            })
+            .collect();
+        assert_eq!(at, [src, src, src, "f(1)", "f", "1", src, src, src, src]);
+    }
+}
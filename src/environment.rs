@@ -1,43 +1,69 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::rc::Rc;
 
-use anyhow::{bail, Ok, Result};
+use crate::{
+    heap,
+    internal_value::{Closure, Lambda, Value},
+    resolve::{Function, Var},
+};
 
-use crate::internal_value::Value;
-
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// The variables visible to a running function: its own frame of locals and
+/// the values captured by its closure.
+#[derive(Debug, Default)]
 pub struct Env {
-    env: HashMap<String, Value>,
-    outer: Option<Rc<RefCell<Env>>>,
+    locals: Vec<Value>,
+    closure: Option<Rc<Closure>>,
 }
 
 impl Env {
-    pub fn new() -> Self {
-        Self {
-            env: HashMap::new(),
-            outer: None,
-        }
+    /// A frame for the top level of a program.
+    pub fn new(frame_size: usize) -> Self {
+        Self::with_closure(frame_size, None)
     }
 
-    pub fn with_outer(outer: Rc<RefCell<Env>>) -> Self {
+    pub fn with_closure(frame_size: usize, closure: Option<Rc<Closure>>) -> Self {
+        // Resolution guarantees every slot is written before it is read.
         Self {
-            env: HashMap::new(),
-            outer: Some(outer),
+            locals: vec![Value::Bool(false); frame_size],
+            closure,
         }
     }
 
-    pub fn get(&self, name: &str) -> Result<Value> {
-        if let Some(val) = self.env.get(name) {
-            Ok(val.clone())
-        } else {
-            if let Some(outer) = &self.outer {
-                outer.borrow().get(name)
-            } else {
-                bail!("undefined variable")
-            }
+    pub fn get(&self, var: Var) -> Value {
+        match var {
+            Var::Local(slot) => self.locals[slot].clone(),
+            Var::Capture(i) => match &self.closure {
+                Some(closure) => closure.captures.borrow()[i].clone(),
+                None => unreachable!("captured variable outside of a closure"),
+            },
         }
     }
 
-    pub fn set(&mut self, name: &str, val: Value) {
-        self.env.insert(name.to_string(), val);
+    pub fn set(&mut self, slot: usize, val: Value) {
+        self.locals[slot] = val;
+    }
+
+    /// The number of local slots in the frame.
+    pub fn frame_size(&self) -> usize {
+        self.locals.len()
+    }
+
+    /// Builds a closure from the captured variables of this frame. A
+    /// recursive function captures itself.
+    pub fn closure(&self, fun: &Rc<Function>) -> Value {
+        let captures = fun
+            .captures
+            .iter()
+            .enumerate()
+            .map(|(i, (_, var))| match fun.recursive {
+                // A placeholder until the closure exists.
+                Some(rec) if rec == i => Value::Bool(false),
+                _ => self.get(*var),
+            })
+            .collect();
+        let closure = heap::alloc(Closure::new(Lambda::Resolved(Rc::clone(fun)), captures));
+        if let Some(rec) = fun.recursive {
+            closure.captures.borrow_mut()[rec] = Value::Lambda(Rc::clone(&closure));
+        }
+        Value::Lambda(closure)
     }
 }
@@ -1,109 +1,489 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, str::FromStr};
 
-use anyhow::{bail, Ok, Result};
+use anyhow::{anyhow, bail, Ok, Result};
 
-use crate::{environment::Env, expression::Expr, internal_value::Value};
+use crate::{
+    budget::{Budget, CancelToken, Limits},
+    bytecode::Module,
+    environment::Env,
+    expression::Expr,
+    heap,
+    internal_value::Value,
+    machine::Machine,
+    resolve::{Code, Resolver},
+    vm::Vm,
+};
+
+/// The stack limit of the stack-safe machine when none is given.
+pub const DEFAULT_STACK_LIMIT: usize = 64 << 20;
+
+/// How `Eval` runs a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The tree-walking evaluator, which recurses on the Rust stack.
+    Tree,
+    /// The stack-safe machine, whose stack may use this many bytes.
+    Machine(usize),
+    /// Compilation to bytecode, run on the VM.
+    Bytecode,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tree" => Ok(Backend::Tree),
+            "machine" => Ok(Backend::Machine(DEFAULT_STACK_LIMIT)),
+            "bytecode" => Ok(Backend::Bytecode),
+            _ => Err(anyhow!("unknown backend {s:?}")),
+        }
+    }
+}
+
+/// The outcome of evaluating an expression, short of a call in tail position.
+enum Step {
+    Done(Value),
+    Call(Value, Value),
+}
 
 pub struct Eval {
-    env: Rc<RefCell<Env>>,
+    /// The frame of the running function.
+    env: RefCell<Env>,
+    backend: Backend,
+    limits: Limits,
+    cancel: Option<CancelToken>,
+    /// What is left of `limits` during an evaluation.
+    budget: RefCell<Budget>,
 }
 
 impl Eval {
     pub fn new() -> Self {
         Self {
-            env: Rc::new(RefCell::new(Env::new())),
+            env: RefCell::new(Env::default()),
+            backend: Backend::Tree,
+            limits: Limits::default(),
+            cancel: None,
+            budget: RefCell::new(Budget::default()),
         }
     }
 
-    pub fn with_env(env: Env) -> Self {
-        Self {
-            env: Rc::new(RefCell::new(env)),
-        }
+    /// An evaluator that keeps its work stack on the heap, so deep non-tail
+    /// recursion fails with an error once the stack grows past `stack_limit`
+    /// bytes instead of overflowing the native stack.
+    pub fn with_stack_limit(stack_limit: usize) -> Self {
+        Self::new().with_backend(Backend::Machine(stack_limit))
+    }
+
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Limits every evaluation, failing with `ResourceExhausted` once one is
+    /// reached.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Makes every evaluation fail with `Cancelled` soon after `token` is
+    /// cancelled, which may happen from another thread.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    fn budget(&self) -> Budget {
+        Budget::new(self.limits, self.cancel.clone())
     }
 
     pub fn eval(&self, ast: &Expr) -> Result<Value> {
-        match &ast {
-            Expr::Int(v) => Ok(Value::Int(*v)),
-            Expr::Bool(v) => Ok(Value::Bool(*v)),
-            Expr::Variable(name) => self.env.borrow().get(name),
-            Expr::Program(prog, ret) => {
-                for expr in prog {
-                    let _ = self.eval(&expr);
-                }
-                self.eval(&ret)
+        let program = Resolver::resolve(ast);
+        match self.backend {
+            Backend::Tree => {}
+            Backend::Machine(stack_limit) => {
+                return Machine::new(&program, stack_limit, self.budget()).run();
             }
-            Expr::BinOp(op, exp1, exp2) => {
-                let v1 = self.eval(&exp1)?;
-                let v2 = self.eval(&exp2)?;
-                match (v1, v2) {
-                    (Value::Int(x), Value::Int(y)) => match op.as_str() {
-                        "+" => Ok(Value::Int(x + y)),
-                        "-" => Ok(Value::Int(x - y)),
-                        "*" => Ok(Value::Int(x * y)),
-                        "/" => Ok(Value::Int(x / y)),
-                        "==" => Ok(Value::Bool(x == y)),
-                        "!=" => Ok(Value::Bool(x != y)),
-                        "<" => Ok(Value::Bool(x < y)),
-                        ">" => Ok(Value::Bool(x > y)),
-                        "<=" => Ok(Value::Bool(x <= y)),
-                        ">=" => Ok(Value::Bool(x >= y)),
-                        _ => bail!("invalid binary operation {}", op),
-                    },
-                    (Value::Bool(x), Value::Bool(y)) => match op.as_str() {
-                        "&&" => Ok(Value::Bool(x && y)),
-                        "||" => Ok(Value::Bool(x || y)),
-                        _ => bail!("invalid binary operation {}", op),
-                    },
-                    _ => {
-                        bail!("invalid binary operation {}", op)
-                    }
+            Backend::Bytecode => return self.eval_module(&Module::compile(&program)),
+        }
+        self.budget.replace(self.budget());
+        let outer = self.env.replace(Env::new(program.frame_size));
+        let ret = self.run(&program.code);
+        *self.env.borrow_mut() = outer;
+        ret
+    }
+
+    /// Runs a program compiled to bytecode, e.g. one loaded from a file.
+    pub fn eval_module(&self, module: &Module) -> Result<Value> {
+        Vm::run(module, &self.budget())
+    }
+
+    fn run(&self, code: &Code) -> Result<Value> {
+        match self.run_tail(code)? {
+            Step::Done(val) => Ok(val),
+            Step::Call(fun, arg) => self.apply(fun, arg),
+        }
+    }
+
+    /// Evaluates `code` up to a call in tail position, which is handed back
+    /// to `apply` instead of being made here, so a chain of tail calls runs
+    /// in constant Rust stack space.
+    fn run_tail(&self, code: &Code) -> Result<Step> {
+        self.budget.borrow().step()?;
+        let val = match code {
+            Code::Int(v) => Value::Int(*v),
+            Code::Bool(v) => Value::Bool(*v),
+            Code::Var(var) => self.env.borrow().get(*var),
+            Code::Unbound(_) => bail!("undefined variable"),
+            Code::Program(prog, ret) => {
+                for code in prog.iter() {
+                    self.run(code)?;
                 }
+                return self.run_tail(ret);
+            }
+            Code::Let(slot, code) => {
+                let val = self.run(code)?;
+                self.env.borrow_mut().set(*slot, val.clone());
+                val
             }
-            Expr::UnaryOp(op, exp1) => {
-                let v1 = self.eval(&exp1)?;
-                match v1 {
-                    Value::Int(x) => match op.as_str() {
-                        "-" => Ok(Value::Int(-x)),
-                        _ => bail!("invalid binary operation {}", op),
-                    },
-                    Value::Bool(x) => match op.as_str() {
-                        "!" => Ok(Value::Bool(!x)),
-                        _ => bail!("invalid binary operation {}", op),
-                    },
-                    _ => {
-                        bail!("invalid binary operation {}", op)
+            Code::BinOp(op, exp1, exp2) => {
+                let v1 = self.run(exp1)?;
+                let v2 = self.run(exp2)?;
+                op.apply(v1, v2)?
+            }
+            Code::Compare(first, rest) => {
+                let mut lhs = self.run(first)?;
+                for (op, code) in rest.iter() {
+                    let rhs = self.run(code)?;
+                    if op.apply(lhs, rhs.clone())? == Value::Bool(false) {
+                        return Ok(Step::Done(Value::Bool(false)));
                     }
+                    lhs = rhs;
                 }
+                Value::Bool(true)
             }
-            Expr::If(cond, exp1, exp2) => {
-                if let Value::Bool(b) = self.eval(&cond)? {
-                    if b {
-                        self.eval(&exp1)
+            Code::UnaryOp(op, exp1) => op.apply(self.run(exp1)?)?,
+            Code::If(cond, exp1, exp2) => {
+                if let Value::Bool(b) = self.run(cond)? {
+                    return if b {
+                        self.run_tail(exp1)
                     } else {
-                        self.eval(&exp2)
-                    }
+                        self.run_tail(exp2)
+                    };
                 } else {
                     bail!("if expression: non-bool condition!");
                 }
             }
-            Expr::Assign(name, _, expr) => {
-                let val = self.eval(&expr)?;
-                self.env.borrow_mut().set(name, val.clone());
-                Ok(val)
+            Code::Lambda(fun) => self.env.borrow().closure(fun),
+            Code::App(fun, var) => {
+                if let Code::Compose(first, then) = fun.as_ref() {
+                    // `(f >> g)(x)` is `g(f(x))`; no composed value is needed.
+                    // As in any call, the function is evaluated first.
+                    let first = self.run(first)?;
+                    let then = self.run(then)?;
+                    let arg = self.run(var)?;
+                    let mid = self.apply(first, arg)?;
+                    return Ok(Step::Call(then, mid));
+                } else {
+                    let fun = self.run(fun)?;
+                    return Ok(Step::Call(fun, self.run(var)?));
+                }
             }
-            Expr::Lambda(var, _, expr) => {
-                let new_env = Env::with_outer(Rc::clone(&self.env));
-                Ok(Value::Lambda(var.clone(), expr.clone(), new_env))
+            Code::Compose(first, then) => {
+                Value::Compose(Box::new(self.run(first)?), Box::new(self.run(then)?))
             }
-            Expr::App(fun, var) => {
-                if let Value::Lambda(arg, expr, env) = self.eval(&fun)? {
-                    let inner_eval = Eval::with_env(env);
-                    inner_eval.env.borrow_mut().set(&arg, self.eval(&var)?);
-                    inner_eval.eval(&expr)
-                } else {
-                    bail!("eval error: application to non-lambda!")
+        };
+        Ok(Step::Done(val))
+    }
+
+    /// Calls `fun`, then keeps making the tail calls its body ends in, each
+    /// one replacing the frame of the previous.
+    fn apply(&self, fun: Value, arg: Value) -> Result<Value> {
+        self.budget.borrow().enter()?;
+        let outer = self.env.take();
+        let ret = self.apply_loop(fun, arg);
+        *self.env.borrow_mut() = outer;
+        self.budget.borrow().leave();
+        ret
+    }
+
+    fn apply_loop(&self, mut fun: Value, mut arg: Value) -> Result<Value> {
+        loop {
+            match fun {
+                Value::Lambda(closure) => {
+                    let body = Rc::clone(closure.fun.resolved());
+                    let mut env = Env::with_closure(body.frame_size, Some(closure));
+                    env.set(0, arg);
+                    *self.env.borrow_mut() = env;
+                    match self.run_tail(&body.body)? {
+                        Step::Done(val) => return Ok(val),
+                        Step::Call(next, next_arg) => (fun, arg) = (next, next_arg),
+                    }
                 }
+                Value::Compose(first, then) => {
+                    arg = self.apply(*first, arg)?;
+                    fun = *then;
+                }
+                _ => bail!("eval error: application to non-lambda!"),
             }
         }
     }
 }
+
+impl Default for Eval {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Recursive closures created during evaluation are freed once the results
+/// that still need them are gone.
+impl Drop for Eval {
+    fn drop(&mut self) {
+        heap::collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, time::Duration};
+
+    use crate::{
+        budget::{CancelToken, Cancelled, Limits, ResourceExhausted},
+        desugar::parse_core,
+        heap,
+        internal_value::Value,
+        types::TypeInfer,
+    };
+
+    use super::Eval;
+
+    fn run(src: &str) -> Value {
+        let expr = parse_core(src).unwrap();
+        TypeInfer::new().infer_type(&expr).unwrap();
+        Eval::new().eval(&expr).unwrap()
+    }
+
+    #[test]
+    fn eval_comparison_chain() {
+        assert_eq!(run("1 < 2 <= 2 < 3"), Value::Bool(true));
+        assert_eq!(run("3 > 2 > 2"), Value::Bool(false));
+        assert_eq!(run("1 == 1 != 2"), Value::Bool(true));
+        assert_eq!(run("let x = 5; 0 <= x - 5 < 10"), Value::Bool(true));
+    }
+
+    #[test]
+    fn eval_pipe_and_compose() {
+        let prelude = "let inc = lambda (x: int) { x + 1 }; let dbl = lambda (x: int) { x * 2 };";
+        assert_eq!(run(&format!("{prelude} 3 |> inc |> dbl")), Value::Int(8));
+        assert_eq!(run(&format!("{prelude} 3 |> inc >> dbl")), Value::Int(8));
+        assert_eq!(run(&format!("{prelude} (inc << dbl)(3)")), Value::Int(7));
+        assert_eq!(
+            run(&format!("{prelude} let both = inc >> dbl; both(both(0))")),
+            Value::Int(6)
+        );
+    }
+
+    #[test]
+    fn eval_sections() {
+        assert_eq!(run("(+)(1)(2)"), Value::Int(3));
+        assert_eq!(run("(* 2)(21)"), Value::Int(42));
+        assert_eq!(run("(10 -)(3)"), Value::Int(7));
+        assert_eq!(
+            run("let x = 4; 3 |> (x >) >> (|| false)"),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            run("let fold = lambda (f) { lambda (z) { f(f(z)(1))(2) } }; fold((+))(0)"),
+            Value::Int(3)
+        );
+    }
+
+    #[test]
+    fn eval_user_operators_use_the_declared_function() {
+        let plus = "let plus = lambda (a) { lambda (b) { a + b } }; infixl 6 <+> = plus;";
+        assert_eq!(
+            run(&format!(
+                "{plus} let plus = lambda (a) {{ lambda (b) {{ a * b }} }}; 2 <+> 3"
+            )),
+            Value::Int(5)
+        );
+        assert_eq!(
+            run(&format!(
+                "{plus} let f = lambda (plus) {{ 2 <+> plus }}; f(3)"
+            )),
+            Value::Int(5)
+        );
+    }
+
+    #[test]
+    fn eval_division_by_zero() {
+        let expr = parse_core("let zero = 0; 1 / zero").unwrap();
+        let err = Eval::new().eval(&expr).unwrap_err();
+        assert_eq!(err.to_string(), "division by zero");
+    }
+
+    #[test]
+    fn eval_closure_captures_defining_scope() {
+        assert_eq!(
+            run("let x = 1; let g = lambda (u: int) { x }; let x = 2; g(0) * 10 + x"),
+            Value::Int(12)
+        );
+        assert_eq!(
+            run("let mk = lambda (n: int) { lambda (u: int) { n } }; let a = mk(1); let b = mk(2); a(0) * 10 + b(0)"),
+            Value::Int(12)
+        );
+        assert_eq!(
+            run("let f = lambda (u: int) { let x = 5; x }; let x = 1; f(0) + x"),
+            Value::Int(6)
+        );
+    }
+
+    #[test]
+    fn eval_closure_ignores_later_definitions() {
+        let expr = parse_core("let g = lambda (u: int) { y }; let y = 1; g(0)").unwrap();
+        assert!(Eval::new().eval(&expr).is_err());
+    }
+
+    #[test]
+    fn eval_recursive_functions() {
+        assert_eq!(
+            run("let f = lambda (n: int) { if (n < 2) { n } else { f(n - 1) + f(n - 2) } }; f(10)"),
+            Value::Int(55)
+        );
+        assert_eq!(
+            run("let x = 3; let x = x + 1; let f = lambda (n: int) { x }; let x = 0; f(x)"),
+            Value::Int(4)
+        );
+    }
+
+    #[test]
+    fn eval_closure_captures_free_variables_only() {
+        let expr = parse_core(
+            "let big = 1; let unused = 2; let k = lambda (x: int) { lambda (y: int) { x + big } }; k(1)",
+        )
+        .unwrap();
+        let Value::Lambda(closure) = Eval::new().eval(&expr).unwrap() else {
+            panic!("expected a closure")
+        };
+        let names: Vec<_> = closure
+            .fun
+            .captures()
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["x", "big"]);
+    }
+
+    #[test]
+    fn eval_closures_share_their_function() {
+        // Both closures come from one evaluation of the inner lambda each,
+        // and only differ in the values they capture.
+        let src = "let mk = lambda (n: int) { lambda (u: int) { n } }; mk(1) >> mk(2)";
+        let Value::Compose(first, then) = run(src) else {
+            panic!("expected a composed value")
+        };
+        let (Value::Lambda(first), Value::Lambda(then)) = (*first, *then) else {
+            panic!("expected closures")
+        };
+        assert!(Rc::ptr_eq(first.fun.resolved(), then.fun.resolved()));
+        assert_eq!(*first.captures.borrow(), [Value::Int(1)]);
+        assert_eq!(*then.captures.borrow(), [Value::Int(2)]);
+    }
+
+    #[test]
+    fn eval_frees_recursive_closures() {
+        let src = "let f = lambda (n: int) { if (n < 1) { 0 } else { f(n - 1) } }; f";
+        let f = run(src);
+        assert_eq!(heap::live_closures(), 1);
+        assert_eq!(heap::collect(), 0);
+        drop(f);
+        assert_eq!(heap::live_closures(), 1);
+        assert_eq!(heap::collect(), 1);
+        assert_eq!(heap::live_closures(), 0);
+
+        run(&src.replace("; f", "; f(10)"));
+        assert_eq!(heap::live_closures(), 0);
+    }
+
+    #[test]
+    fn eval_tail_calls_in_constant_stack() {
+        assert_eq!(
+            run("let count = lambda (n: int) { if (n == 0) { 0 } else { count(n - 1) } }; count(100000)"),
+            Value::Int(0)
+        );
+        assert_eq!(
+            run("let sum = lambda (acc: int) { lambda (n: int) { if (n == 0) { acc } else { sum(acc + n)(n - 1) } } }; sum(0)(100000)"),
+            Value::Int(5000050000)
+        );
+        assert_eq!(
+            run("let dec = lambda (x: int) { x - 1 }; let count = lambda (n: int) { if (n < 1) { n } else { n |> dec >> count } }; count(100000)"),
+            Value::Int(0)
+        );
+    }
+
+    #[test]
+    fn eval_resource_limits() {
+        let limits = Limits {
+            steps: Some(10_000),
+            call_depth: Some(100),
+            timeout: None,
+        };
+        let exhausted = |src: &str, limits: Limits| {
+            let expr = parse_core(src).unwrap();
+            [Eval::new(), Eval::with_stack_limit(1 << 30)].map(|eval| {
+                let err = eval.with_limits(limits).eval(&expr).unwrap_err();
+                *err.downcast_ref::<ResourceExhausted>().unwrap()
+            })
+        };
+        let forever = "let f = lambda (x: int) { f(x) }; f(1)";
+        assert_eq!(
+            exhausted(forever, limits),
+            [ResourceExhausted::Steps(10_000); 2]
+        );
+        assert_eq!(
+            exhausted(
+                "let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(1000)",
+                limits
+            ),
+            [ResourceExhausted::CallDepth(100); 2]
+        );
+        let timeout = Duration::from_millis(10);
+        assert_eq!(
+            exhausted(
+                forever,
+                Limits {
+                    timeout: Some(timeout),
+                    ..Limits::default()
+                }
+            ),
+            [ResourceExhausted::Timeout(timeout); 2]
+        );
+
+        let expr = parse_core(
+            "let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(99)",
+        )
+        .unwrap();
+        assert_eq!(
+            Eval::new().with_limits(limits).eval(&expr).unwrap(),
+            Value::Int(99)
+        );
+    }
+
+    #[test]
+    fn eval_cancellation() {
+        let expr = parse_core("let f = lambda (x: int) { f(x) }; f(1)").unwrap();
+        for eval in [Eval::new(), Eval::with_stack_limit(1 << 30)] {
+            let cancel = CancelToken::new();
+            let stop = cancel.clone();
+            let stopper = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                stop.cancel();
+            });
+            let err = eval.with_cancel(cancel).eval(&expr).unwrap_err();
+            assert_eq!(err.downcast_ref(), Some(&Cancelled));
+            stopper.join().unwrap();
+        }
+    }
+}
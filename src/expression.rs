@@ -1,19 +1,29 @@
 use core::fmt;
 
-use crate::types::Type;
+use crate::{
+    ident::Ident,
+    operator::{BinOp, UnOp},
+    types::Type,
+    visit::Visitor,
+};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Expr {
     Int(i64),
     Bool(bool),
-    Variable(String),
+    Variable(Ident),
     Program(Vec<Expr>, Box<Expr>),
-    BinOp(String, Box<Expr>, Box<Expr>),
-    UnaryOp(String, Box<Expr>),
+    BinOp(BinOp, Box<Expr>, Box<Expr>),
+    /// A comparison chain `a < b <= c`, meaning `a < b && b <= c` with each
+    /// operand evaluated at most once.
+    Compare(Box<Expr>, Vec<(BinOp, Expr)>),
+    UnaryOp(UnOp, Box<Expr>),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
-    Assign(String, Option<Type>, Box<Expr>),
-    Lambda(String, Option<Type>, Box<Expr>),
+    Assign(Ident, Option<Type>, Box<Expr>),
+    Lambda(Ident, Option<Type>, Box<Expr>),
     App(Box<Expr>, Box<Expr>),
+    /// `f >> g`: applies `f`, then `g`.
+    Compose(Box<Expr>, Box<Expr>),
 }
 
 impl Expr {
@@ -25,78 +35,234 @@ impl Expr {
         Expr::Bool(b)
     }
 
-    pub fn variable(name: String) -> Self {
+    pub fn variable(name: Ident) -> Self {
         Expr::Variable(name)
     }
 
-    pub fn assign(name: String, ty: Option<Type>, expr: Expr) -> Self {
+    pub fn assign(name: Ident, ty: Option<Type>, expr: Expr) -> Self {
         Expr::Assign(name, ty, Box::new(expr))
     }
 
-    pub fn binop(name: String, exp1: Expr, exp2: Expr) -> Self {
-        Expr::BinOp(name, Box::new(exp1), Box::new(exp2))
+    pub fn binop(op: BinOp, exp1: Expr, exp2: Expr) -> Self {
+        Expr::BinOp(op, Box::new(exp1), Box::new(exp2))
     }
 
-    pub fn unaryop(name: String, expr: Expr) -> Self {
-        Expr::UnaryOp(name, Box::new(expr))
+    pub fn compare(first: Expr, rest: Vec<(BinOp, Expr)>) -> Self {
+        Expr::Compare(Box::new(first), rest)
+    }
+
+    pub fn unaryop(op: UnOp, expr: Expr) -> Self {
+        Expr::UnaryOp(op, Box::new(expr))
     }
 
     pub fn app(fun: Expr, arg: Expr) -> Self {
         Expr::App(Box::new(fun), Box::new(arg))
     }
 
+    pub fn compose(first: Expr, then: Expr) -> Self {
+        Expr::Compose(Box::new(first), Box::new(then))
+    }
+
     pub fn if_expr(cond: Expr, expr: Expr, elseexp: Expr) -> Self {
         Expr::If(Box::new(cond), Box::new(expr), Box::new(elseexp))
     }
 
-    pub fn lambda(name: String, argty: Option<Type>, expr: Expr) -> Self {
+    pub fn lambda(name: Ident, argty: Option<Type>, expr: Expr) -> Self {
         Expr::Lambda(name, argty, Box::new(expr))
     }
 
     pub fn program(prog: Vec<Expr>, ret: Expr) -> Self {
         Expr::Program(prog, Box::new(ret))
     }
+
+    /// Variables referenced but not bound inside this expression, in order
+    /// of first occurrence.
+    pub fn free_vars(&self) -> Vec<Ident> {
+        let mut free_vars = FreeVars::default();
+        free_vars.visit_expr(self);
+        free_vars.free
+    }
+}
+
+#[derive(Default)]
+struct FreeVars {
+    bound: Vec<Ident>,
+    free: Vec<Ident>,
+}
+
+impl Visitor for FreeVars {
+    fn visit_variable(&mut self, name: Ident) {
+        if !self.bound.contains(&name) && !self.free.contains(&name) {
+            self.free.push(name);
+        }
+    }
+
+    fn visit_program(&mut self, prog: &[Expr], ret: &Expr) {
+        let depth = self.bound.len();
+        for expr in prog {
+            self.visit_expr(expr);
+            if let Expr::Assign(name, _, _) = expr {
+                self.bound.push(*name);
+            }
+        }
+        self.visit_expr(ret);
+        self.bound.truncate(depth);
+    }
+
+    fn visit_assign(&mut self, name: Ident, _ty: Option<&Type>, expr: &Expr) {
+        // A function may refer to itself.
+        if let Expr::Lambda(..) = expr {
+            self.bound.push(name);
+            self.visit_expr(expr);
+            self.bound.pop();
+        } else {
+            self.visit_expr(expr);
+        }
+    }
+
+    fn visit_lambda(&mut self, param: Ident, _ty: Option<&Type>, body: &Expr) {
+        self.bound.push(param);
+        self.visit_expr(body);
+        self.bound.pop();
+    }
 }
 
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Expr::Int(v) => write!(f, "Int({})", v),
-            Expr::Bool(v) => write!(f, "{}", v),
-            Expr::Variable(name) => write!(f, "{}", name),
-            Expr::Program(v, ret) => write!(
-                f,
-                "{} {}",
-                v.iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<_>>()
-                    .join(" "),
-                ret.to_string()
-            ),
-            Expr::BinOp(op, exp1, exp2) => write!(f, "({} {op} {})", exp1, exp2),
-            Expr::UnaryOp(op, expr) => write!(f, "{op}{}", expr),
-            Expr::If(cond, exp1, exp2) => {
-                write!(f, "if ({}) {{ {} }} else {{ {} }}", cond, exp1, exp2)
-            }
-            Expr::Assign(ident, ty, expr) => {
-                let tt = if ty.is_some() {
-                    ty.as_ref().unwrap().to_string()
-                } else {
-                    "?".to_string()
-                };
-                write!(f, "let {ident}: {} = {};", tt, expr)
-            }
-            Expr::Lambda(var, ty, expr) => {
-                let tt = if ty.is_some() {
-                    ty.as_ref().unwrap().to_string()
-                } else {
-                    "?".to_string()
-                };
-                write!(f, "lambda ({var}:{}) {{ {} }}", tt, expr)
-            }
-            Expr::App(fun, var) => {
-                write!(f, "{}({})", fun, var)
+        let mut printer = Printer { f, result: Ok(()) };
+        printer.visit_expr(self);
+        printer.result
+    }
+}
+
+/// Writes an expression out, up to the first error.
+struct Printer<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    result: fmt::Result,
+}
+
+impl Printer<'_, '_> {
+    fn write(&mut self, args: fmt::Arguments) {
+        if self.result.is_ok() {
+            self.result = self.f.write_fmt(args);
+        }
+    }
+
+    fn write_type(&mut self, ty: Option<&Type>) {
+        match ty {
+            Some(ty) => self.write(format_args!("{ty}")),
+            None => self.write(format_args!("?")),
+        }
+    }
+}
+
+impl Visitor for Printer<'_, '_> {
+    fn visit_int(&mut self, v: i64) {
+        self.write(format_args!("Int({v})"));
+    }
+
+    fn visit_bool(&mut self, v: bool) {
+        self.write(format_args!("{v}"));
+    }
+
+    fn visit_variable(&mut self, name: Ident) {
+        self.write(format_args!("{name}"));
+    }
+
+    fn visit_program(&mut self, prog: &[Expr], ret: &Expr) {
+        for (i, expr) in prog.iter().enumerate() {
+            if i > 0 {
+                self.write(format_args!(" "));
             }
+            self.visit_expr(expr);
+        }
+        self.write(format_args!(" "));
+        self.visit_expr(ret);
+    }
+
+    fn visit_binop(&mut self, op: BinOp, exp1: &Expr, exp2: &Expr) {
+        self.write(format_args!("("));
+        self.visit_expr(exp1);
+        self.write(format_args!(" {op} "));
+        self.visit_expr(exp2);
+        self.write(format_args!(")"));
+    }
+
+    fn visit_compare(&mut self, first: &Expr, rest: &[(BinOp, Expr)]) {
+        self.write(format_args!("("));
+        self.visit_expr(first);
+        for (op, expr) in rest {
+            self.write(format_args!(" {op} "));
+            self.visit_expr(expr);
         }
+        self.write(format_args!(")"));
+    }
+
+    fn visit_unaryop(&mut self, op: UnOp, expr: &Expr) {
+        self.write(format_args!("{op}"));
+        self.visit_expr(expr);
+    }
+
+    fn visit_if(&mut self, cond: &Expr, exp1: &Expr, exp2: &Expr) {
+        self.write(format_args!("if ("));
+        self.visit_expr(cond);
+        self.write(format_args!(") {{ "));
+        self.visit_expr(exp1);
+        self.write(format_args!(" }} else {{ "));
+        self.visit_expr(exp2);
+        self.write(format_args!(" }}"));
+    }
+
+    fn visit_assign(&mut self, name: Ident, ty: Option<&Type>, expr: &Expr) {
+        self.write(format_args!("let {name}: "));
+        self.write_type(ty);
+        self.write(format_args!(" = "));
+        self.visit_expr(expr);
+        self.write(format_args!(";"));
+    }
+
+    fn visit_lambda(&mut self, param: Ident, ty: Option<&Type>, body: &Expr) {
+        self.write(format_args!("lambda ({param}:"));
+        self.write_type(ty);
+        self.write(format_args!(") {{ "));
+        self.visit_expr(body);
+        self.write(format_args!(" }}"));
+    }
+
+    fn visit_app(&mut self, fun: &Expr, arg: &Expr) {
+        self.visit_expr(fun);
+        self.write(format_args!("("));
+        self.visit_expr(arg);
+        self.write(format_args!(")"));
+    }
+
+    fn visit_compose(&mut self, first: &Expr, then: &Expr) {
+        self.write(format_args!("("));
+        self.visit_expr(first);
+        self.write(format_args!(" >> "));
+        self.visit_expr(then);
+        self.write(format_args!(")"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::desugar::parse_core;
+
+    fn free_vars(src: &str) -> Vec<&'static str> {
+        let free = parse_core(src).unwrap().free_vars();
+        free.into_iter().map(|name| name.as_str()).collect()
+    }
+
+    #[test]
+    fn free_vars_of_programs() {
+        assert_eq!(free_vars("x + y * x"), ["x", "y"]);
+        assert_eq!(free_vars("lambda (x) { x + y }"), ["y"]);
+        assert_eq!(free_vars("let a = b; let b = a; a + b + c"), ["b", "c"]);
+        assert_eq!(
+            free_vars("let f = lambda (n) { f(n) + g(n) }; f(h)"),
+            ["g", "h"]
+        );
+        assert_eq!(free_vars("let x = x + 1; x"), ["x"]);
     }
 }
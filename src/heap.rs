@@ -0,0 +1,139 @@
+//! Cycle collection for closures.
+//!
+//! Closures are reference counted, but a recursive function captures itself,
+//! and reference counting alone never frees such a cycle. Every closure is
+//! registered here; `collect` finds the closures that are reachable only from
+//! other closures and breaks their cycles by dropping their captures.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use crate::internal_value::{Closure, Value};
+
+/// Collection runs when the number of registered closures reaches this, and
+/// the limit grows with the number of survivors.
+const MIN_THRESHOLD: usize = 1024;
+
+struct Heap {
+    closures: RefCell<Vec<Weak<Closure>>>,
+    threshold: Cell<usize>,
+}
+
+thread_local! {
+    static HEAP: Heap = const {
+        Heap {
+            closures: RefCell::new(vec![]),
+            threshold: Cell::new(MIN_THRESHOLD),
+        }
+    };
+}
+
+/// Registers a new closure, collecting garbage first if the heap has grown
+/// past its threshold.
+pub fn alloc(closure: Closure) -> Rc<Closure> {
+    if HEAP.with(|heap| heap.closures.borrow().len() >= heap.threshold.get()) {
+        collect();
+        HEAP.with(|heap| {
+            let live = heap.closures.borrow().len();
+            heap.threshold.set(MIN_THRESHOLD.max(2 * live));
+        });
+    }
+    let closure = Rc::new(closure);
+    HEAP.with(|heap| heap.closures.borrow_mut().push(Rc::downgrade(&closure)));
+    closure
+}
+
+/// The number of closures currently alive on this thread.
+pub fn live_closures() -> usize {
+    HEAP.with(|heap| {
+        let mut closures = heap.closures.borrow_mut();
+        closures.retain(|closure| closure.strong_count() > 0);
+        closures.len()
+    })
+}
+
+/// Frees every closure that is unreachable from outside the closure graph,
+/// returning how many were freed.
+///
+/// A closure is live if some reference to it does not come from another
+/// closure's captures: subtracting those internal references from the
+/// reference count leaves the references held by variables, values on the
+/// Rust stack or the embedder. Everything reachable from such a closure
+/// survives.
+pub fn collect() -> usize {
+    let closures: Vec<Rc<Closure>> = HEAP.with(|heap| {
+        let mut closures = heap.closures.borrow_mut();
+        closures.retain(|closure| closure.strong_count() > 0);
+        closures.iter().filter_map(Weak::upgrade).collect()
+    });
+    let index: HashMap<*const Closure, usize> = closures
+        .iter()
+        .enumerate()
+        .map(|(i, closure)| (Rc::as_ptr(closure), i))
+        .collect();
+
+    // The upgraded `Rc`s above hold one reference each.
+    let mut external: Vec<usize> = closures
+        .iter()
+        .map(|closure| Rc::strong_count(closure) - 1)
+        .collect();
+    for closure in &closures {
+        for child in children(closure) {
+            if let Some(&i) = index.get(&Rc::as_ptr(&child)) {
+                external[i] -= 1;
+            }
+        }
+    }
+
+    let mut live = vec![false; closures.len()];
+    let mut stack: Vec<usize> = (0..closures.len()).filter(|&i| external[i] > 0).collect();
+    while let Some(i) = stack.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        for child in children(&closures[i]) {
+            if let Some(&j) = index.get(&Rc::as_ptr(&child)) {
+                stack.push(j);
+            }
+        }
+    }
+
+    let garbage: Vec<Vec<Value>> = closures
+        .iter()
+        .zip(&live)
+        .filter(|(_, &live)| !live)
+        .map(|(closure, _)| closure.captures.take())
+        .collect();
+    let freed = garbage.len();
+    drop(garbage);
+    drop(closures);
+    HEAP.with(|heap| {
+        heap.closures
+            .borrow_mut()
+            .retain(|closure| closure.strong_count() > 0)
+    });
+    freed
+}
+
+fn children(closure: &Closure) -> Vec<Rc<Closure>> {
+    let mut children = vec![];
+    for val in closure.captures.borrow().iter() {
+        value_children(val, &mut children);
+    }
+    children
+}
+
+fn value_children(val: &Value, children: &mut Vec<Rc<Closure>>) {
+    match val {
+        Value::Int(_) | Value::Bool(_) => {}
+        Value::Lambda(closure) => children.push(Rc::clone(closure)),
+        Value::Compose(first, then) => {
+            value_children(first, children);
+            value_children(then, children);
+        }
+    }
+}
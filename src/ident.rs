@@ -0,0 +1,107 @@
+//! Interned identifiers.
+//!
+//! An `Ident` is a small copyable handle for a name, so comparing, hashing
+//! and copying identifiers never touches the string. The interner is shared
+//! by the whole process; embedders can intern the names they define up front
+//! with `Ident::intern`.
+//!
+//! Interned names are never freed. Each distinct name is stored once, so the
+//! memory held is bounded by the total length of the distinct names ever
+//! interned: running the same scripts again, however often, adds nothing,
+//! but a host interning an unbounded stream of fresh names grows without
+//! limit. Looking a name up takes no lock, only interning a new one does.
+
+use core::fmt;
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex, OnceLock},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Ident(u32);
+
+/// The ids of the interned names, only used to intern.
+static IDS: LazyLock<Mutex<HashMap<&'static str, Ident>>> = LazyLock::new(Default::default);
+
+/// The names by id. Chunk `k` holds the `2^k` ids from `2^k - 1` on and is
+/// never moved once allocated, so names can be read without the lock.
+static NAMES: [OnceLock<Box<[OnceLock<&'static str>]>>; 32] = [const { OnceLock::new() }; 32];
+
+/// The chunk of `NAMES` holding the id, and its index in the chunk.
+fn slot(id: u32) -> (usize, usize) {
+    let n = id as u64 + 1;
+    let chunk = n.ilog2();
+    (chunk as usize, (n - (1 << chunk)) as usize)
+}
+
+impl Ident {
+    pub fn intern(name: &str) -> Self {
+        let mut ids = IDS.lock().unwrap();
+        if let Some(&ident) = ids.get(name) {
+            return ident;
+        }
+        let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+        assert!(ids.len() < u32::MAX as usize, "too many identifiers");
+        let ident = Ident(ids.len() as u32);
+        let (chunk, index) = slot(ident.0);
+        let chunk = NAMES[chunk].get_or_init(|| (0..1 << chunk).map(|_| OnceLock::new()).collect());
+        chunk[index].set(name).unwrap();
+        ids.insert(name, ident);
+        ident
+    }
+
+    pub fn as_str(self) -> &'static str {
+        let (chunk, index) = slot(self.0);
+        // An ident only exists once its name is stored.
+        NAMES[chunk]
+            .get()
+            .and_then(|chunk| chunk[index].get())
+            .unwrap()
+    }
+}
+
+impl From<&str> for Ident {
+    fn from(name: &str) -> Self {
+        Ident::intern(name)
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slot, Ident};
+
+    #[test]
+    fn intern_is_idempotent() {
+        let a = Ident::intern("alpha");
+        assert_eq!(a, Ident::intern("alpha"));
+        assert_ne!(a, Ident::intern("beta"));
+        assert_eq!(a.as_str(), "alpha");
+        assert_eq!(Ident::from("beta").to_string(), "beta");
+    }
+
+    #[test]
+    fn names_are_found_across_chunks() {
+        assert_eq!(slot(0), (0, 0));
+        assert_eq!(slot(1), (1, 0));
+        assert_eq!(slot(6), (2, 3));
+        assert_eq!(slot(u32::MAX - 1), (31, (1 << 31) - 1));
+        let idents: Vec<_> = (0..100)
+            .map(|i| Ident::intern(&format!("name{i}")))
+            .collect();
+        for (i, ident) in idents.iter().enumerate() {
+            assert_eq!(ident.as_str(), format!("name{i}"));
+        }
+    }
+}
@@ -1,12 +1,96 @@
 use core::fmt;
+use std::{cell::RefCell, ptr, rc::Rc};
 
-use crate::{environment::Env, expression::Expr};
+use crate::{
+    bytecode::Proto,
+    ident::Ident,
+    resolve::{Function, Var},
+};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Value {
     Int(i64),
     Bool(bool),
-    Lambda(String, Box<Expr>, Env),
+    Lambda(Rc<Closure>),
+    /// The composition `f >> g` of two function values.
+    Compose(Box<Value>, Box<Value>),
+}
+
+/// The code a closure runs: resolved code for the tree-walking evaluators,
+/// or a compiled function for the bytecode VM.
+#[derive(Debug, Clone)]
+pub enum Lambda {
+    Resolved(Rc<Function>),
+    Compiled(Rc<Proto>),
+}
+
+impl Lambda {
+    pub fn param(&self) -> Ident {
+        match self {
+            Lambda::Resolved(fun) => fun.param,
+            Lambda::Compiled(proto) => proto.param,
+        }
+    }
+
+    pub fn captures(&self) -> &[(Ident, Var)] {
+        match self {
+            Lambda::Resolved(fun) => &fun.captures,
+            Lambda::Compiled(proto) => &proto.captures,
+        }
+    }
+
+    /// The resolved function; closures never outlive the evaluation that
+    /// created them, so the tree-walking evaluators only ever see these.
+    pub fn resolved(&self) -> &Rc<Function> {
+        match self {
+            Lambda::Resolved(fun) => fun,
+            Lambda::Compiled(_) => unreachable!("compiled closure in a tree-walking evaluator"),
+        }
+    }
+}
+
+/// A flat closure: the function together with the values of its free
+/// variables, captured when the lambda was evaluated.
+pub struct Closure {
+    pub fun: Lambda,
+    /// Indexed like `fun.captures()`. A recursive function captures itself,
+    /// so this is completed after the closure has been allocated.
+    pub captures: RefCell<Vec<Value>>,
+}
+
+impl Closure {
+    pub fn new(fun: Lambda, captures: Vec<Value>) -> Self {
+        Self {
+            fun,
+            captures: RefCell::new(captures),
+        }
+    }
+}
+
+/// Closures are compared by identity.
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Eq for Closure {}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Closure")
+            .field("param", &self.fun.param())
+            .field(
+                "captures",
+                &self
+                    .fun
+                    .captures()
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
 }
 
 impl fmt::Display for Value {
@@ -14,7 +98,8 @@ impl fmt::Display for Value {
         match self {
             Value::Bool(b) => write!(f, "{b}"),
             Value::Int(i) => write!(f, "{i}"),
-            Value::Lambda(v, _, _) => write!(f, "lambda ({v})"),
+            Value::Lambda(closure) => write!(f, "lambda ({})", closure.fun.param()),
+            Value::Compose(first, then) => write!(f, "{first} >> {then}"),
         }
     }
 }
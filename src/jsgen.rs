@@ -0,0 +1,406 @@
+//! Compilation of type-checked expressions to JavaScript.
+//!
+//! A program becomes an ES module whose default export is the program's
+//! value. Functions become arrow functions and `let`s become `const`s, kept
+//! under their own names where JavaScript allows it. Ints are `BigInt`s
+//! wrapped to 64 bits after every operation, so arithmetic overflows the
+//! way it does in `Eval`.
+
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::{bail, Ok, Result};
+
+use crate::{
+    expression::Expr,
+    ident::Ident,
+    operator::{BinOp, UnOp},
+};
+
+/// Runtime helpers, emitted ahead of the program when it uses them.
+const HELPERS: [(&str, &str); 5] = [
+    ("$wrap", "const $wrap = (n) => BigInt.asIntN(64, n);\n"),
+    (
+        "$div",
+        "const $div = (a, b) => {\n  if (b === 0n) throw new Error(\"division by zero\");\n  return BigInt.asIntN(64, a / b);\n};\n",
+    ),
+    // Both operands are evaluated, as in `Eval`.
+    ("$and", "const $and = (a, b) => a && b;\n"),
+    ("$or", "const $or = (a, b) => a || b;\n"),
+    ("$compose", "const $compose = (f, g) => (x) => g(f(x));\n"),
+];
+
+/// Names a variable cannot have in JavaScript, and the globals the helpers use.
+const RESERVED: &[&str] = &[
+    "BigInt",
+    "Error",
+    "arguments",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "eval",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "with",
+    "yield",
+];
+
+const INDENT: &str = "  ";
+
+pub struct JsGen {
+    /// The JavaScript names of the variables in scope, innermost last.
+    scope: Vec<(Ident, String)>,
+    /// How many variables have been given each name.
+    names: HashMap<String, usize>,
+    helpers: [bool; HELPERS.len()],
+}
+
+impl JsGen {
+    /// Compiles a type-checked program to an ES module.
+    pub fn compile(expr: &Expr) -> Result<String> {
+        let mut gen = JsGen {
+            scope: vec![],
+            names: HashMap::new(),
+            helpers: [false; HELPERS.len()],
+        };
+        let mut body = String::new();
+        match expr {
+            Expr::Program(prog, ret) => {
+                for stmt in prog {
+                    gen.stmt(&mut body, stmt, 0)?;
+                }
+                writeln!(body, "export default {};", gen.expr(ret, 0)?)?;
+            }
+            expr => writeln!(body, "export default {};", gen.expr(expr, 0)?)?,
+        }
+        let mut module = String::from("// Generated by rscript.\n");
+        for ((_, helper), used) in HELPERS.iter().zip(gen.helpers) {
+            if used {
+                module.push_str(helper);
+            }
+        }
+        if gen.helpers.contains(&true) {
+            module.push('\n');
+        }
+        module.push_str(&body);
+        Ok(module)
+    }
+
+    /// Writes one statement of a program at the given depth.
+    fn stmt(&mut self, out: &mut String, stmt: &Expr, depth: usize) -> Result<()> {
+        let indent = INDENT.repeat(depth);
+        match stmt {
+            Expr::Assign(name, _, exp) => {
+                // Only a function can refer to the variable it defines.
+                let (js, code) = if let Expr::Lambda(..) = exp.as_ref() {
+                    let js = self.bind(*name);
+                    (js, self.expr(exp, depth)?)
+                } else {
+                    let code = self.expr(exp, depth)?;
+                    (self.bind(*name), code)
+                };
+                writeln!(out, "{indent}const {js} = {code};")?;
+            }
+            stmt => writeln!(out, "{indent}{};", self.expr(stmt, depth)?)?,
+        }
+        Ok(())
+    }
+
+    /// Compiles an expression appearing at the given depth of indentation.
+    fn expr(&mut self, expr: &Expr, depth: usize) -> Result<String> {
+        Ok(match expr {
+            Expr::Int(v) if *v < 0 => format!("({v}n)"),
+            Expr::Int(v) => format!("{v}n"),
+            Expr::Bool(v) => v.to_string(),
+            Expr::Variable(name) => match self.scope.iter().rev().find(|(n, _)| n == name) {
+                Some((_, js)) => js.clone(),
+                None => bail!("undefined variable {name}"),
+            },
+            Expr::Program(prog, ret) => {
+                format!("(() => {})()", self.block(prog, ret, depth)?)
+            }
+            // Nothing after a `let` out of statement position can see it.
+            Expr::Assign(_, _, exp) => self.expr(exp, depth)?,
+            Expr::BinOp(op, exp1, exp2) => {
+                let lhs = self.expr(exp1, depth)?;
+                let rhs = self.expr(exp2, depth)?;
+                self.binop(*op, &lhs, &rhs)
+            }
+            Expr::Compare(first, rest) => {
+                let first = self.expr(first, depth)?;
+                let rest = rest
+                    .iter()
+                    .map(|(op, exp)| Ok((*op, self.expr(exp, depth)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                compare_chain(first, &rest)
+            }
+            Expr::UnaryOp(UnOp::Neg, exp) => {
+                let code = self.expr(exp, depth)?;
+                format!("{}(-{code})", self.helper("$wrap"))
+            }
+            Expr::UnaryOp(UnOp::Not, exp) => format!("!{}", self.expr(exp, depth)?),
+            Expr::If(cond, exp1, exp2) => format!(
+                "({} ? {} : {})",
+                self.expr(cond, depth)?,
+                self.expr(exp1, depth)?,
+                self.expr(exp2, depth)?
+            ),
+            Expr::Lambda(param, _, body) => {
+                let len = self.scope.len();
+                let param = self.bind(*param);
+                let body = match body.as_ref() {
+                    Expr::Program(prog, ret) => self.block(prog, ret, depth)?,
+                    body => self.expr(body, depth)?,
+                };
+                self.scope.truncate(len);
+                format!("({param}) => {body}")
+            }
+            Expr::App(fun, arg) => {
+                let fun = match fun.as_ref() {
+                    Expr::Variable(_) | Expr::App(..) => self.expr(fun, depth)?,
+                    fun => format!("({})", self.expr(fun, depth)?),
+                };
+                format!("{fun}({})", self.expr(arg, depth)?)
+            }
+            Expr::Compose(first, then) => {
+                let first = self.expr(first, depth)?;
+                let then = self.expr(then, depth)?;
+                format!("{}({first}, {then})", self.helper("$compose"))
+            }
+        })
+    }
+
+    /// Compiles a program to a function body `{ ... }` at the given depth.
+    fn block(&mut self, prog: &[Expr], ret: &Expr, depth: usize) -> Result<String> {
+        let len = self.scope.len();
+        let mut out = String::from("{\n");
+        for stmt in prog {
+            self.stmt(&mut out, stmt, depth + 1)?;
+        }
+        let ret = self.expr(ret, depth + 1)?;
+        writeln!(out, "{}return {ret};", INDENT.repeat(depth + 1))?;
+        write!(out, "{}}}", INDENT.repeat(depth))?;
+        self.scope.truncate(len);
+        Ok(out)
+    }
+
+    fn binop(&mut self, op: BinOp, lhs: &str, rhs: &str) -> String {
+        match op {
+            BinOp::Add | BinOp::Sub | BinOp::Mul => {
+                format!("{}({lhs} {} {rhs})", self.helper("$wrap"), op.symbol())
+            }
+            BinOp::Div => format!("{}({lhs}, {rhs})", self.helper("$div")),
+            BinOp::And => format!("{}({lhs}, {rhs})", self.helper("$and")),
+            BinOp::Or => format!("{}({lhs}, {rhs})", self.helper("$or")),
+            op => format!("({lhs} {} {rhs})", comparison(op)),
+        }
+    }
+
+    /// Marks a runtime helper as used, returning its name.
+    fn helper(&mut self, name: &'static str) -> &'static str {
+        let index = HELPERS.iter().position(|(n, _)| *n == name).unwrap();
+        self.helpers[index] = true;
+        name
+    }
+
+    /// Brings a variable into scope under a JavaScript name of its own: its
+    /// own name unless that is taken or reserved. Our identifiers have no
+    /// `_`, so the suffixed names cannot clash with them.
+    fn bind(&mut self, name: Ident) -> String {
+        let base = if RESERVED.contains(&name.as_str()) {
+            format!("{name}_")
+        } else {
+            name.to_string()
+        };
+        let count = self.names.entry(base.clone()).or_insert(0);
+        let js = match *count {
+            0 => base,
+            n => format!("{base}_{n}"),
+        };
+        *count += 1;
+        self.scope.push((name, js.clone()));
+        js
+    }
+}
+
+fn comparison(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Eq => "===",
+        BinOp::Ne => "!==",
+        op => op.symbol(),
+    }
+}
+
+/// `a < b <= c` as `(($0, $1) => $0 < $1 && (($2) => $1 <= $2)(c))(a, b)`,
+/// which evaluates each operand once and stops at the first comparison that
+/// fails. The temporaries cannot clash with our identifiers.
+fn compare_chain(first: String, rest: &[(BinOp, String)]) -> String {
+    let mut code = String::new();
+    for (i, (op, _)) in rest.iter().enumerate().rev() {
+        let test = format!("${i} {} ${}", comparison(*op), i + 1);
+        code = if code.is_empty() {
+            test
+        } else {
+            format!("{test} && {code}")
+        };
+        let (params, args) = if i == 0 {
+            ("$0, $1".to_string(), format!("{first}, {}", rest[0].1))
+        } else {
+            (format!("${}", i + 1), rest[i].1.clone())
+        };
+        code = format!("(({params}) => {code})({args})");
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use crate::{
+        corpus::{found, FAILING, PROGRAMS},
+        desugar::parse_core,
+        eval::Eval,
+        tokenize::{Token, Tokenizer},
+        types::TypeInfer,
+    };
+
+    use super::{JsGen, RESERVED};
+
+    /// Runs a generated module with node, printing its value or error.
+    fn run_js(name: &str, module: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rscript-jsgen-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{name}.mjs"));
+        fs::write(&path, module).unwrap();
+        let runner = format!(
+            "import({:?}).then((m) => console.log(String(m.default)), (e) => console.log(e.message))",
+            format!("file://{}", path.display())
+        );
+        let output = Command::new("node")
+            .args(["-e", &runner])
+            .output()
+            .expect("cannot run node");
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// The reserved names that are identifiers in our language.
+    fn reserved_identifiers() -> Vec<&'static str> {
+        RESERVED
+            .iter()
+            .copied()
+            .filter(|name| matches!(Tokenizer::new(name).tokenize()[..], [(Token::Ident(_), _)]))
+            .collect()
+    }
+
+    /// A program binding every reserved identifier, each to one more than
+    /// the one before.
+    fn reserved_program() -> String {
+        let names = reserved_identifiers();
+        let mut src = format!("let {} = 1;", names[0]);
+        for pair in names.windows(2) {
+            src.push_str(&format!(" let {} = {} + 1;", pair[1], pair[0]));
+        }
+        src.push_str(&format!(" {}", names[names.len() - 1]));
+        src
+    }
+
+    fn eval(src: &str) -> String {
+        let expr = parse_core(src).unwrap();
+        TypeInfer::new().infer_type(&expr).unwrap();
+        match Eval::new().eval(&expr) {
+            Ok(val) => val.to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn jsgen_matches_eval() {
+        if !found("node") {
+            return;
+        }
+        let reserved = reserved_program();
+        let programs = PROGRAMS.iter().chain(FAILING).copied().chain([
+            // BigInts wrap to 64 bits at the boundary.
+            "4611686018427387904 * 2",
+            "let min = 0 - 9223372036854775807 - 1; -min",
+            "let min = 0 - 9223372036854775807 - 1; min - 1",
+            "let min = 0 - 9223372036854775807 - 1; min / -1",
+            &reserved,
+        ]);
+        for (i, src) in programs.enumerate() {
+            let expr = parse_core(src).unwrap();
+            let module = JsGen::compile(&expr).unwrap();
+            let js = run_js(&format!("case{i}"), &module);
+            assert_eq!(js, eval(src), "{src}\n{module}");
+        }
+    }
+
+    #[test]
+    fn jsgen_renames_reserved_names() {
+        let names = reserved_identifiers();
+        assert!(names.contains(&"yield") && names.contains(&"with"));
+        let module = JsGen::compile(&parse_core(&reserved_program()).unwrap()).unwrap();
+        for name in names {
+            assert!(
+                module.contains(&format!("const {name}_ = ")),
+                "{name}\n{module}"
+            );
+        }
+    }
+
+    #[test]
+    fn jsgen_output_is_readable() {
+        let expr =
+            parse_core("let f = lambda (n: int) { let m = n * 2; m + 1 }; f(1) > 2").unwrap();
+        assert_eq!(
+            JsGen::compile(&expr).unwrap(),
+            "// Generated by rscript.
+const $wrap = (n) => BigInt.asIntN(64, n);
+
+const f = (n) => {
+  const m = $wrap(n * 2n);
+  return $wrap(m + 1n);
+};
+export default (f(1n) > 2n);
+"
+        );
+    }
+}
@@ -1,15 +1,44 @@
-use eval::Eval;
+use std::time::Duration;
+
+use budget::{Budget, CancelToken, Limits};
+use desugar::desugar;
+use eval::{Backend, Eval, DEFAULT_STACK_LIMIT};
+use expression::Expr;
+use jsgen::JsGen;
+use machine::Machine;
 use parse::Parser;
-use types::TypeInfer;
+use resolve::Resolver;
+use span::Spans;
+use types::{Type, TypeInfer, TypeTable};
 use wasm_bindgen::prelude::*;
 
-mod environment;
-mod eval;
-mod expression;
-mod internal_value;
-mod parse;
-mod tokenize;
-mod types;
+pub mod anf;
+pub mod budget;
+pub mod bytecode;
+pub mod cgen;
+#[cfg(test)]
+mod corpus;
+pub mod desugar;
+pub mod environment;
+pub mod eval;
+pub mod expression;
+pub mod heap;
+pub mod ident;
+pub mod internal_value;
+pub mod jsgen;
+pub mod machine;
+pub mod operator;
+pub mod optimize;
+pub mod parse;
+pub mod resolve;
+pub mod serialize;
+pub mod span;
+pub mod syntax;
+pub mod tokenize;
+pub mod types;
+pub mod visit;
+pub mod vm;
+pub mod watgen;
 
 #[wasm_bindgen]
 extern "C" {
@@ -21,16 +50,193 @@ pub fn greet(name: &str) {
     alert(&format!("Hello, {}!", name));
 }
 
+/// Scripts run from JavaScript use the stack-safe evaluator unless the
+/// caller picks another backend.
+const SCRIPT_BACKEND: Backend = Backend::Machine(DEFAULT_STACK_LIMIT);
+
+/// The limits on scripts run from JavaScript, unless the caller sets its own.
+const SCRIPT_LIMITS: Limits = Limits {
+    steps: Some(100_000_000),
+    call_depth: Some(100_000),
+    timeout: Some(Duration::from_secs(10)),
+};
+
 #[wasm_bindgen]
 pub fn eval_script(line: &str) -> JsValue {
-    match Parser::new(line).prog() {
-        Ok(stmt) => match TypeInfer::new().infer_type(&stmt) {
-            Ok(_) => match Eval::new().eval(&stmt) {
-                Ok(val) => val.to_string().into(),
-                Err(err) => err.to_string().into(),
-            },
-            Err(err) => err.to_string().into(),
-        },
+    run_script(line, SCRIPT_BACKEND, SCRIPT_LIMITS)
+}
+
+/// Evaluates a script on the named backend: "tree", "machine" or
+/// "bytecode".
+#[wasm_bindgen]
+pub fn eval_script_with_backend(line: &str, backend: &str) -> JsValue {
+    match backend.parse() {
+        Ok(backend) => run_script(line, backend, SCRIPT_LIMITS),
         Err(err) => err.to_string().into(),
     }
 }
+
+/// Evaluates a script with its own limits on the number of steps, the call
+/// depth and the time in milliseconds. An omitted limit keeps its default.
+#[wasm_bindgen]
+pub fn eval_script_with_limits(
+    line: &str,
+    steps: Option<u32>,
+    call_depth: Option<u32>,
+    timeout_ms: Option<u32>,
+) -> JsValue {
+    let limits = Limits {
+        steps: steps.map(u64::from).or(SCRIPT_LIMITS.steps),
+        call_depth: call_depth.map(|n| n as usize).or(SCRIPT_LIMITS.call_depth),
+        timeout: timeout_ms
+            .map(|ms| Duration::from_millis(ms.into()))
+            .or(SCRIPT_LIMITS.timeout),
+    };
+    run_script(line, SCRIPT_BACKEND, limits)
+}
+
+/// A script that type checks.
+pub struct Checked {
+    pub stmt: Expr,
+    pub spans: Spans,
+    pub types: TypeTable,
+}
+
+impl Checked {
+    /// The type of the innermost expression at byte `offset` of the script.
+    pub fn type_at(&self, offset: usize) -> Option<&Type> {
+        self.types.node(self.spans.innermost(offset)?)
+    }
+}
+
+/// Parses and type checks a script, returning its core with the spans and
+/// types of its nodes. Type errors are `TypeError`s pointing at the source.
+pub fn check(src: &str) -> anyhow::Result<Checked> {
+    let (stmt, spans) = desugar(&Parser::new(src).program()?);
+    let mut infer = TypeInfer::with_spans(spans.clone());
+    infer.infer_type(&stmt)?;
+    Ok(Checked {
+        stmt,
+        spans,
+        types: infer.types(),
+    })
+}
+
+fn run_script(line: &str, backend: Backend, limits: Limits) -> JsValue {
+    let result = check(line).and_then(|checked| {
+        Eval::new()
+            .with_backend(backend)
+            .with_limits(limits)
+            .eval(&checked.stmt)
+    });
+    match result {
+        Ok(val) => val.to_string().into(),
+        Err(err) => err.to_string().into(),
+    }
+}
+
+/// Compiles a script to an ES module whose default export is its value,
+/// returning the module or the error message.
+#[wasm_bindgen]
+pub fn compile_script_to_js(line: &str) -> Result<String, JsValue> {
+    let checked = check(line).map_err(|err| err.to_string())?;
+    JsGen::compile(&checked.stmt).map_err(|err| err.to_string().into())
+}
+
+/// The type of the innermost expression at byte `offset` of a script, for
+/// an editor to show on hover; `undefined` if the script does not type
+/// check or there is no expression there. This checks the whole script; an
+/// `Evaluation` answers from the types it found when it was created.
+#[wasm_bindgen]
+pub fn type_at(line: &str, offset: usize) -> Option<String> {
+    Some(check(line).ok()?.type_at(offset)?.to_string())
+}
+
+/// A script evaluated a slice at a time, so that JavaScript keeps control
+/// between slices and can cancel the evaluation, e.g. from a "Stop" button.
+#[wasm_bindgen]
+pub struct Evaluation {
+    machine: Machine,
+    cancel: CancelToken,
+    checked: Checked,
+}
+
+#[wasm_bindgen]
+impl Evaluation {
+    /// Parses and type checks `line`, failing with the error message.
+    #[wasm_bindgen(constructor)]
+    pub fn new(line: &str) -> Result<Evaluation, JsValue> {
+        let checked = check(line).map_err(|err| err.to_string())?;
+        let cancel = CancelToken::new();
+        // The user can stop a sliced evaluation, so it has no timeout.
+        let limits = Limits {
+            timeout: None,
+            ..SCRIPT_LIMITS
+        };
+        let budget = Budget::new(limits, Some(cancel.clone()));
+        Ok(Evaluation {
+            machine: Machine::new(
+                &Resolver::resolve(&checked.stmt),
+                DEFAULT_STACK_LIMIT,
+                budget,
+            ),
+            cancel,
+            checked,
+        })
+    }
+
+    /// Runs the next `steps` steps, returning the result or error message
+    /// once the evaluation is over and `undefined` while it is not.
+    pub fn run(&mut self, steps: u32) -> Option<String> {
+        match self.machine.run_for(steps.into()) {
+            Ok(Some(val)) => Some(val.to_string()),
+            Ok(None) => None,
+            Err(err) => Some(err.to_string()),
+        }
+    }
+
+    /// Makes the next slice fail as cancelled.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// The type of the innermost expression at byte `offset` of the script,
+    /// as for `type_at`, without checking it again.
+    pub fn type_at(&self, offset: usize) -> Option<String> {
+        Some(self.checked.type_at(offset)?.to_string())
+    }
+}
+
+/// Recursive closures the evaluation created are freed with it, whether it
+/// finished or not.
+impl Drop for Evaluation {
+    fn drop(&mut self) {
+        self.machine.stop();
+        heap::collect();
+    }
+}
+
+/// The number of closures kept alive by previous evaluations.
+#[wasm_bindgen]
+pub fn live_closures() -> usize {
+    heap::live_closures()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+
+    #[test]
+    fn checked_scripts_know_their_types() {
+        let checked = check("let f = lambda (x) { x + 1 }; f(2) > 0").unwrap();
+        let type_at = |offset| checked.type_at(offset).map(|ty| ty.to_string());
+        assert_eq!(type_at(30).as_deref(), Some("(int -> int)"));
+        assert_eq!(type_at(32).as_deref(), Some("int"));
+        assert_eq!(type_at(35).as_deref(), Some("bool"));
+        assert!(check("let x = 1 + true; x").is_err());
+        let Err(err) = check("infixl 6 <+> = plus; 1") else {
+            panic!("an operator of an unbound function was declared")
+        };
+        assert!(err.to_string().contains("undefined variable plus"), "{err}");
+    }
+}
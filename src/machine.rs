@@ -0,0 +1,448 @@
+//! A stack-safe evaluator: an abstract machine whose continuation is an
+//! explicit stack of frames on the heap instead of the Rust call stack, so
+//! recursion depth is bounded only by the memory the stack may use.
+//!
+//! The machine owns all of its state, so an evaluation can also be run in
+//! slices of a few steps at a time and resumed later.
+
+use std::{mem, rc::Rc};
+
+use anyhow::{bail, Ok, Result};
+
+use crate::{
+    budget::{Budget, ResourceExhausted},
+    environment::Env,
+    internal_value::Value,
+    operator::{BinOp, UnOp},
+    resolve::{Code, Program},
+};
+
+/// What to do with the value of the code being evaluated.
+enum Kont {
+    /// Run a program from the statement at the index, then its result.
+    Program(Rc<Code>, usize),
+    Let(usize),
+    /// Evaluate the right operand.
+    BinOpRhs(BinOp, Rc<Code>),
+    /// Apply the operator to the evaluated left operand.
+    BinOp(BinOp, Value),
+    /// Compare against the operand at the index of a comparison chain.
+    Compare(Rc<Code>, usize),
+    /// Test the evaluated left operand against the operand at the index.
+    CompareRhs(Rc<Code>, usize, Value),
+    UnaryOp(UnOp),
+    If(Rc<Code>, Rc<Code>),
+    /// Evaluate the argument of a call.
+    Arg(Rc<Code>),
+    /// Call the function with the evaluated argument.
+    Call(Value),
+    /// `(first >> then)(arg)`: evaluate `then` once `first` is known.
+    ComposeFirst(Rc<Code>, Rc<Code>),
+    /// Evaluate the argument once `first` and `then` are known.
+    ComposeThen(Value, Rc<Code>),
+    /// Call the evaluated `first` with the argument, then `then` with the
+    /// result.
+    ComposeArg(Value, Value),
+    /// Build a composed value from the evaluated `first`.
+    Compose(Rc<Code>),
+    ComposeValue(Value),
+    /// Restore the frame of the caller once a call returns.
+    Return(Env),
+}
+
+impl Kont {
+    /// An estimate of the memory the frame holds.
+    fn size(&self) -> usize {
+        let frame = match self {
+            Kont::Return(env) => env.frame_size() * mem::size_of::<Value>(),
+            _ => 0,
+        };
+        mem::size_of::<Self>() + frame
+    }
+}
+
+enum State {
+    Eval(Rc<Code>),
+    Return(Value),
+}
+
+pub struct Machine {
+    env: Env,
+    konts: Vec<Kont>,
+    /// `None` once the evaluation has finished.
+    state: Option<State>,
+    /// The error the evaluation failed with, if it did.
+    error: Option<String>,
+    /// The estimated size of `konts`, in bytes.
+    stack_size: usize,
+    stack_limit: usize,
+    budget: Budget,
+}
+
+impl Machine {
+    /// An evaluation of `program` that fails once the continuation stack
+    /// would use more than `stack_limit` bytes or `budget` runs out.
+    pub fn new(program: &Program, stack_limit: usize, budget: Budget) -> Self {
+        Self {
+            env: Env::new(program.frame_size),
+            konts: Vec::new(),
+            state: Some(State::Eval(Rc::clone(&program.code))),
+            error: None,
+            stack_size: 0,
+            stack_limit,
+            budget,
+        }
+    }
+
+    /// Runs the evaluation to the end.
+    pub fn run(&mut self) -> Result<Value> {
+        loop {
+            if let Some(val) = self.run_for(u64::MAX)? {
+                return Ok(val);
+            }
+        }
+    }
+
+    /// Runs the evaluation for at most `steps` steps, returning its value if
+    /// it finished and `None` if it can be resumed. Once it has failed, it
+    /// fails again with the same error.
+    pub fn run_for(&mut self, steps: u64) -> Result<Option<Value>> {
+        let Some(state) = self.state.take() else {
+            match &self.error {
+                Some(err) => bail!("{err}"),
+                None => bail!("evaluation already finished"),
+            }
+        };
+        let res = self.steps(state, steps);
+        if let Err(err) = &res {
+            self.error = Some(err.to_string());
+        }
+        // The steps leave a state only if the evaluation can go on.
+        if self.state.is_none() {
+            self.stop();
+        }
+        res
+    }
+
+    fn steps(&mut self, mut state: State, steps: u64) -> Result<Option<Value>> {
+        self.budget.check()?;
+        for _ in 0..steps {
+            state = match state {
+                State::Eval(code) => self.step(code)?,
+                State::Return(val) => match self.pop() {
+                    Some(kont) => self.resume(kont, val)?,
+                    None => return Ok(Some(val)),
+                },
+            }
+        }
+        self.state = Some(state);
+        Ok(None)
+    }
+
+    /// Ends the evaluation, dropping the values it holds.
+    pub fn stop(&mut self) {
+        self.state = None;
+        while self.pop().is_some() {}
+        self.env = Env::new(0);
+    }
+
+    fn push(&mut self, kont: Kont) -> Result<()> {
+        self.stack_size += kont.size();
+        if self.stack_size > self.stack_limit {
+            return Err(ResourceExhausted::Stack(self.stack_limit).into());
+        }
+        if let Kont::Return(_) = kont {
+            self.budget.enter()?;
+        }
+        self.konts.push(kont);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Kont> {
+        let kont = self.konts.pop()?;
+        self.stack_size -= kont.size();
+        if let Kont::Return(_) = kont {
+            self.budget.leave();
+        }
+        Some(kont)
+    }
+
+    /// Starts evaluating `code`.
+    fn step(&mut self, code: Rc<Code>) -> Result<State> {
+        self.budget.step()?;
+        let val = match code.as_ref() {
+            Code::Int(v) => Value::Int(*v),
+            Code::Bool(v) => Value::Bool(*v),
+            Code::Var(var) => self.env.get(*var),
+            Code::Unbound(_) => bail!("undefined variable"),
+            Code::Program(..) => return self.program(code, 0),
+            Code::Let(slot, exp) => {
+                self.push(Kont::Let(*slot))?;
+                return Ok(State::Eval(Rc::clone(exp)));
+            }
+            Code::BinOp(op, exp1, exp2) => {
+                self.push(Kont::BinOpRhs(*op, Rc::clone(exp2)))?;
+                return Ok(State::Eval(Rc::clone(exp1)));
+            }
+            Code::Compare(first, _) => {
+                let first = Rc::clone(first);
+                self.push(Kont::Compare(code, 0))?;
+                return Ok(State::Eval(first));
+            }
+            Code::UnaryOp(op, exp1) => {
+                self.push(Kont::UnaryOp(*op))?;
+                return Ok(State::Eval(Rc::clone(exp1)));
+            }
+            Code::If(cond, exp1, exp2) => {
+                self.push(Kont::If(Rc::clone(exp1), Rc::clone(exp2)))?;
+                return Ok(State::Eval(Rc::clone(cond)));
+            }
+            Code::Lambda(fun) => self.env.closure(fun),
+            Code::App(fun, var) => {
+                if let Code::Compose(first, then) = fun.as_ref() {
+                    // `(f >> g)(x)` is `g(f(x))`; no composed value is needed.
+                    self.push(Kont::ComposeFirst(Rc::clone(then), Rc::clone(var)))?;
+                    return Ok(State::Eval(Rc::clone(first)));
+                }
+                self.push(Kont::Arg(Rc::clone(var)))?;
+                return Ok(State::Eval(Rc::clone(fun)));
+            }
+            Code::Compose(first, then) => {
+                self.push(Kont::Compose(Rc::clone(then)))?;
+                return Ok(State::Eval(Rc::clone(first)));
+            }
+        };
+        Ok(State::Return(val))
+    }
+
+    /// Continues the program `code` from statement `next`.
+    fn program(&mut self, code: Rc<Code>, next: usize) -> Result<State> {
+        let Code::Program(prog, ret) = code.as_ref() else {
+            unreachable!("continuing a program that is not one");
+        };
+        match prog.get(next) {
+            Some(stmt) => {
+                let stmt = Rc::clone(stmt);
+                self.push(Kont::Program(code, next + 1))?;
+                Ok(State::Eval(stmt))
+            }
+            None => Ok(State::Eval(Rc::clone(ret))),
+        }
+    }
+
+    /// Continues with `val` as the value of the code `kont` was waiting on.
+    fn resume(&mut self, kont: Kont, val: Value) -> Result<State> {
+        let val = match kont {
+            Kont::Program(code, next) => return self.program(code, next),
+            Kont::Let(slot) => {
+                self.env.set(slot, val.clone());
+                val
+            }
+            Kont::BinOpRhs(op, exp2) => {
+                self.push(Kont::BinOp(op, val))?;
+                return Ok(State::Eval(exp2));
+            }
+            Kont::BinOp(op, lhs) => op.apply(lhs, val)?,
+            Kont::Compare(code, next) => return self.compare(code, next, val),
+            Kont::CompareRhs(code, i, lhs) => {
+                let Code::Compare(_, rest) = code.as_ref() else {
+                    unreachable!("continuing a comparison that is not one");
+                };
+                if rest[i].0.apply(lhs, val.clone())? == Value::Bool(false) {
+                    Value::Bool(false)
+                } else {
+                    return self.compare(code, i + 1, val);
+                }
+            }
+            Kont::UnaryOp(op) => op.apply(val)?,
+            Kont::If(exp1, exp2) => match val {
+                Value::Bool(true) => return Ok(State::Eval(exp1)),
+                Value::Bool(false) => return Ok(State::Eval(exp2)),
+                _ => bail!("if expression: non-bool condition!"),
+            },
+            Kont::Arg(var) => {
+                self.push(Kont::Call(val))?;
+                return Ok(State::Eval(var));
+            }
+            Kont::Call(fun) => return self.call(fun, val),
+            Kont::ComposeFirst(then, var) => {
+                self.push(Kont::ComposeThen(val, var))?;
+                return Ok(State::Eval(then));
+            }
+            Kont::ComposeThen(first, var) => {
+                self.push(Kont::ComposeArg(first, val))?;
+                return Ok(State::Eval(var));
+            }
+            Kont::ComposeArg(first, then) => {
+                self.push(Kont::Call(then))?;
+                return self.call(first, val);
+            }
+            Kont::Compose(then) => {
+                self.push(Kont::ComposeValue(val))?;
+                return Ok(State::Eval(then));
+            }
+            Kont::ComposeValue(first) => Value::Compose(Box::new(first), Box::new(val)),
+            Kont::Return(env) => {
+                self.env = env;
+                val
+            }
+        };
+        Ok(State::Return(val))
+    }
+
+    fn call(&mut self, fun: Value, arg: Value) -> Result<State> {
+        match fun {
+            Value::Lambda(closure) => {
+                let fun = Rc::clone(closure.fun.resolved());
+                let mut env = Env::with_closure(fun.frame_size, Some(closure));
+                env.set(0, arg);
+                let caller = mem::replace(&mut self.env, env);
+                // A call in tail position returns straight to the caller of
+                // the function making it, whose frame is restored instead.
+                if !matches!(self.konts.last(), Some(Kont::Return(_))) {
+                    self.push(Kont::Return(caller))?;
+                }
+                Ok(State::Eval(Rc::clone(&fun.body)))
+            }
+            Value::Compose(first, then) => {
+                // A call of `lambda (x) { then(first(x)) }`, whose frame
+                // holds nothing.
+                if !matches!(self.konts.last(), Some(Kont::Return(_))) {
+                    let caller = mem::replace(&mut self.env, Env::new(0));
+                    self.push(Kont::Return(caller))?;
+                }
+                self.push(Kont::Call(*then))?;
+                self.call(*first, arg)
+            }
+            _ => bail!("eval error: application to non-lambda!"),
+        }
+    }
+
+    /// Compares `lhs` against operand `next` of the comparison chain `code`.
+    fn compare(&mut self, code: Rc<Code>, next: usize, lhs: Value) -> Result<State> {
+        let Code::Compare(_, rest) = code.as_ref() else {
+            unreachable!("continuing a comparison that is not one");
+        };
+        match rest.get(next) {
+            Some((_, rhs)) => {
+                let rhs = Rc::clone(rhs);
+                self.push(Kont::CompareRhs(code, next, lhs))?;
+                Ok(State::Eval(rhs))
+            }
+            None => Ok(State::Return(Value::Bool(true))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        budget::{Budget, CancelToken, Cancelled, Limits},
+        corpus::PROGRAMS,
+        desugar::parse_core,
+        eval::Eval,
+        heap,
+        internal_value::Value,
+        resolve::Resolver,
+        types::TypeInfer,
+    };
+
+    use super::Machine;
+
+    const LIMIT: usize = 1 << 30;
+
+    fn run(src: &str, stack_limit: usize) -> anyhow::Result<Value> {
+        let expr = parse_core(src).unwrap();
+        TypeInfer::new().infer_type(&expr).unwrap();
+        Eval::with_stack_limit(stack_limit).eval(&expr)
+    }
+
+    #[test]
+    fn machine_matches_eval() {
+        for src in PROGRAMS {
+            let expr = parse_core(src).unwrap();
+            assert_eq!(
+                Eval::with_stack_limit(LIMIT).eval(&expr).unwrap(),
+                Eval::new().eval(&expr).unwrap(),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn machine_runs_deep_recursion() {
+        assert_eq!(
+            run(
+                "let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(200000)",
+                LIMIT
+            )
+            .unwrap(),
+            Value::Int(200000)
+        );
+        assert_eq!(
+            run("let count = lambda (n: int) { if (n == 0) { 0 } else { count(n - 1) } }; count(200000)", 1024).unwrap(),
+            Value::Int(0)
+        );
+    }
+
+    #[test]
+    fn machine_reports_stack_limit() {
+        let err = run(
+            "let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(200000)",
+            1 << 16,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "stack limit of 65536 bytes exceeded");
+        let expr = parse_core("let zero = 0; 1 / zero").unwrap();
+        let err = Eval::with_stack_limit(LIMIT).eval(&expr).unwrap_err();
+        assert_eq!(err.to_string(), "division by zero");
+    }
+
+    #[test]
+    fn machine_runs_in_slices() {
+        let expr = parse_core(
+            "let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(1000)",
+        )
+        .unwrap();
+        let program = Resolver::resolve(&expr);
+        let mut machine = Machine::new(&program, LIMIT, Budget::default());
+        let mut slices = 1;
+        while machine.run_for(100).unwrap().is_none() {
+            slices += 1;
+        }
+        assert!(slices > 10);
+        assert!(machine.run_for(100).is_err());
+
+        let expr = parse_core("let zero = 0; 1 / zero").unwrap();
+        let mut machine = Machine::new(&Resolver::resolve(&expr), LIMIT, Budget::default());
+        for _ in 0..2 {
+            let err = machine.run_for(100).unwrap_err();
+            assert_eq!(err.to_string(), "division by zero");
+        }
+
+        let cancel = CancelToken::new();
+        let budget = Budget::new(Limits::default(), Some(cancel.clone()));
+        let mut machine = Machine::new(&program, LIMIT, budget);
+        assert_eq!(machine.run_for(100).unwrap(), None);
+        cancel.cancel();
+        let err = machine.run_for(100).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Cancelled));
+        let err = machine.run_for(100).unwrap_err();
+        assert_eq!(err.to_string(), Cancelled.to_string());
+    }
+
+    #[test]
+    fn machine_stop_frees_closures() {
+        let expr = parse_core(
+            "let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(1000)",
+        )
+        .unwrap();
+        let mut machine = Machine::new(&Resolver::resolve(&expr), LIMIT, Budget::default());
+        assert_eq!(machine.run_for(100).unwrap(), None);
+        heap::collect();
+        assert_eq!(heap::live_closures(), 1);
+        machine.stop();
+        assert_eq!(heap::collect(), 1);
+        assert_eq!(heap::live_closures(), 0);
+    }
+}
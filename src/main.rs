@@ -1,19 +1,137 @@
-use anyhow::{Context, Ok, Result};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-use eval::Eval;
-use parse::Parser;
-use types::TypeInfer;
+use anyhow::{bail, Context, Ok, Result};
 
-mod environment;
-mod eval;
-mod expression;
-mod internal_value;
-mod parse;
-mod tokenize;
-mod types;
+use rscript::{
+    bytecode::Module,
+    cgen::CGen,
+    desugar::parse_core,
+    eval::{Backend, Eval},
+    optimize,
+    resolve::Resolver,
+    serialize,
+    types::{TypeError, TypeInfer},
+    watgen::WatGen,
+    Checked,
+};
+
+const USAGE: &str = "usage:
+    rscript run [--backend tree|machine|bytecode] [--optimize] [--dump-optimized]
+                <script or compiled file>
+        compiled files always run on the bytecode backend
+    rscript compile <script> [-o <out>]
+    rscript wat <script> [-o <out>]
+    rscript build <script> [-o <out>]
+        compile the C with -O2, so that all tail calls run in constant stack";
 
 fn main() -> Result<()> {
-    let stmt = Parser::new(
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => demo(),
+        Some("run") => run(&args[1..]),
+        Some("compile") => compile(&args[1..]),
+        Some("wat") => wat(&args[1..]),
+        Some("build") => build(&args[1..]),
+        Some(_) => bail!(USAGE),
+    }
+}
+
+/// `rscript run`: evaluates a script file and prints its value. With
+/// `--optimize` the script is optimised first, and `--dump-optimized` also
+/// prints the optimised tree to stderr. Compiled files run on the bytecode
+/// VM.
+fn run(args: &[String]) -> Result<()> {
+    let mut backend = None;
+    let (mut optimize, mut dump) = (false, false);
+    let mut script = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--backend" => backend = Some(args.next().context(USAGE)?.parse()?),
+            "--optimize" => optimize = true,
+            "--dump-optimized" => (optimize, dump) = (true, true),
+            _ if script.is_none() => script = Some(arg),
+            _ => bail!(USAGE),
+        }
+    }
+    let script = script.context(USAGE)?;
+    let bytes = fs::read(script).with_context(|| format!("cannot read {script}"))?;
+    let val = if serialize::is_bytecode(&bytes) {
+        if backend.is_some_and(|backend| backend != Backend::Bytecode) {
+            bail!("{script} is compiled and only runs on the bytecode backend");
+        }
+        let module = Module::from_bytes(&bytes).context("Load Error")?;
+        Eval::new().eval_module(&module)
+    } else {
+        let eval = Eval::new().with_backend(backend.unwrap_or(Backend::Tree));
+        let mut stmt = check(&String::from_utf8(bytes)?)?.stmt;
+        if optimize {
+            stmt = optimize::optimize(&stmt);
+        }
+        if dump {
+            eprintln!("{stmt}");
+        }
+        eval.eval(&stmt)
+    };
+    println!("{}", val.context("Evaluation Error")?);
+    Ok(())
+}
+
+/// `rscript compile`: compiles a script to a bytecode file, by default next
+/// to the script with the extension `rsbc`.
+fn compile(args: &[String]) -> Result<()> {
+    let (src, out) = input_output(args, "rsbc")?;
+    let checked = check(&src)?;
+    let module = Module::compile_with_spans(&Resolver::resolve(&checked.stmt), &checked.spans);
+    fs::write(&out, module.to_bytes()).with_context(|| format!("cannot write {}", out.display()))
+}
+
+/// `rscript wat`: compiles a script to a WebAssembly text module, by
+/// default next to the script with the extension `wat`.
+fn wat(args: &[String]) -> Result<()> {
+    let (src, out) = input_output(args, "wat")?;
+    let module = WatGen::compile(&check(&src)?.stmt);
+    fs::write(&out, module).with_context(|| format!("cannot write {}", out.display()))
+}
+
+/// `rscript build`: compiles a script to a C program printing its value,
+/// by default next to the script with the extension `c`.
+fn build(args: &[String]) -> Result<()> {
+    let (src, out) = input_output(args, "c")?;
+    let program = CGen::compile(&check(&src)?.stmt);
+    fs::write(&out, program).with_context(|| format!("cannot write {}", out.display()))
+}
+
+/// Reads the script of `<script> [-o <out>]`, returning it with the output
+/// path, which defaults to the script's with the extension `ext`.
+fn input_output(args: &[String], ext: &str) -> Result<(String, PathBuf)> {
+    let (script, out) = match args {
+        [script] => (script, Path::new(script).with_extension(ext)),
+        [script, o, out] if o == "-o" => (script, out.into()),
+        _ => bail!(USAGE),
+    };
+    let src = fs::read_to_string(script).with_context(|| format!("cannot read {script}"))?;
+    Ok((src, out))
+}
+
+/// Checks a script with `rscript::check`, telling parse and type errors
+/// apart.
+fn check(src: &str) -> Result<Checked> {
+    rscript::check(src).map_err(|err| {
+        let what = if err.is::<TypeError>() {
+            "Type Error"
+        } else {
+            "Parse Error"
+        };
+        err.context(what)
+    })
+}
+
+fn demo() -> Result<()> {
+    let stmt = parse_core(
         r#"
         let w = true;
         let f = lambda (w) {
@@ -25,7 +143,6 @@ fn main() -> Result<()> {
         f
         "#,
     )
-    .prog()
     .context("Parse Error")?;
     dbg!(&stmt.to_string());
 
@@ -36,17 +153,15 @@ fn main() -> Result<()> {
         .context("Evaluation Error")?
         .to_string());
 
-    let stmt = Parser::new(
+    let stmt = parse_core(
         r#"
         let f: int -> int = lambda (n: int) {
             if(n == 1 || n == 2) { 1 } else { f(n-1) + f(n-2) }
         };
         f(10)
         "#,
-    )
-    .prog()?;
+    )?;
     dbg!(&stmt.to_string());
-    // dbg!(TypeInfer::new().infer_type(&stmt)?);
 
     dbg!(Eval::new().eval(&stmt)?.to_string());
     Ok(())
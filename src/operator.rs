@@ -0,0 +1,160 @@
+use core::fmt;
+
+use anyhow::{bail, Ok, Result};
+
+use crate::{internal_value::Value, types::Type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+    None,
+    /// Comparison chains: `a < b < c` is read as `a < b && b < c`, with `b`
+    /// evaluated once.
+    Chain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+/// One row of the operator table.
+pub struct BinOpInfo {
+    pub symbol: &'static str,
+    pub prec: u8,
+    pub assoc: Assoc,
+    pub operand: Type,
+    pub result: Type,
+}
+
+impl BinOp {
+    pub const ALL: [BinOp; 12] = [
+        BinOp::Add,
+        BinOp::Sub,
+        BinOp::Mul,
+        BinOp::Div,
+        BinOp::Eq,
+        BinOp::Ne,
+        BinOp::Lt,
+        BinOp::Gt,
+        BinOp::Le,
+        BinOp::Ge,
+        BinOp::And,
+        BinOp::Or,
+    ];
+
+    pub fn info(self) -> BinOpInfo {
+        let (symbol, prec, assoc, operand, result) = match self {
+            BinOp::Or => ("||", 2, Assoc::Left, Type::Bool, Type::Bool),
+            BinOp::And => ("&&", 3, Assoc::Left, Type::Bool, Type::Bool),
+            BinOp::Eq => ("==", 4, Assoc::Chain, Type::Int, Type::Bool),
+            BinOp::Ne => ("!=", 4, Assoc::Chain, Type::Int, Type::Bool),
+            BinOp::Lt => ("<", 5, Assoc::Chain, Type::Int, Type::Bool),
+            BinOp::Gt => (">", 5, Assoc::Chain, Type::Int, Type::Bool),
+            BinOp::Le => ("<=", 5, Assoc::Chain, Type::Int, Type::Bool),
+            BinOp::Ge => (">=", 5, Assoc::Chain, Type::Int, Type::Bool),
+            BinOp::Add => ("+", 6, Assoc::Left, Type::Int, Type::Int),
+            BinOp::Sub => ("-", 6, Assoc::Left, Type::Int, Type::Int),
+            BinOp::Mul => ("*", 7, Assoc::Left, Type::Int, Type::Int),
+            BinOp::Div => ("/", 7, Assoc::Left, Type::Int, Type::Int),
+        };
+        BinOpInfo {
+            symbol,
+            prec,
+            assoc,
+            operand,
+            result,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        self.info().symbol
+    }
+
+    /// Integer arithmetic wraps around on overflow.
+    pub fn apply(self, v1: Value, v2: Value) -> Result<Value> {
+        match (v1, v2) {
+            (Value::Int(x), Value::Int(y)) => match self {
+                BinOp::Add => Ok(Value::Int(x.wrapping_add(y))),
+                BinOp::Sub => Ok(Value::Int(x.wrapping_sub(y))),
+                BinOp::Mul => Ok(Value::Int(x.wrapping_mul(y))),
+                BinOp::Div if y == 0 => bail!("division by zero"),
+                BinOp::Div => Ok(Value::Int(x.wrapping_div(y))),
+                BinOp::Eq => Ok(Value::Bool(x == y)),
+                BinOp::Ne => Ok(Value::Bool(x != y)),
+                BinOp::Lt => Ok(Value::Bool(x < y)),
+                BinOp::Gt => Ok(Value::Bool(x > y)),
+                BinOp::Le => Ok(Value::Bool(x <= y)),
+                BinOp::Ge => Ok(Value::Bool(x >= y)),
+                BinOp::And | BinOp::Or => bail!("{self} expects bool operands"),
+            },
+            (Value::Bool(x), Value::Bool(y)) => match self {
+                BinOp::And => Ok(Value::Bool(x && y)),
+                BinOp::Or => Ok(Value::Bool(x || y)),
+                _ => bail!("{self} expects int operands"),
+            },
+            _ => bail!("invalid operands for {self}"),
+        }
+    }
+}
+
+impl UnOp {
+    pub const ALL: [UnOp; 2] = [UnOp::Neg, UnOp::Not];
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnOp::Neg => "-",
+            UnOp::Not => "!",
+        }
+    }
+
+    /// The type of the operand, which is also the type of the result.
+    pub fn operand_type(self) -> Type {
+        match self {
+            UnOp::Neg => Type::Int,
+            UnOp::Not => Type::Bool,
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|op| op.symbol() == symbol)
+    }
+
+    pub fn apply(self, v: Value) -> Result<Value> {
+        match (self, v) {
+            (UnOp::Neg, Value::Int(x)) => Ok(Value::Int(x.wrapping_neg())),
+            (UnOp::Not, Value::Bool(x)) => Ok(Value::Bool(!x)),
+            _ => bail!("invalid operand for {self}"),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+impl fmt::Display for UnOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
@@ -0,0 +1,484 @@
+//! Optimisation passes over type-checked expressions.
+//!
+//! The passes rely on the program having type checked: an operand is known
+//! to have the type its operator expects, and every variable is bound.
+//! They never change what a program evaluates to, including the errors it
+//! fails with.
+
+use crate::{
+    expression::Expr,
+    ident::Ident,
+    internal_value::Value,
+    operator::{BinOp, UnOp},
+    visit::{self, Fold, Visitor},
+};
+
+/// The largest function, in nodes, that is inlined where it is called.
+const INLINE_SIZE: usize = 16;
+
+/// The most times the passes are run over a program.
+const ROUNDS: usize = 8;
+
+/// Runs the passes until they change nothing, as each can give the other
+/// more to do.
+pub fn optimize(expr: &Expr) -> Expr {
+    let mut expr = fold_constants(expr);
+    for _ in 0..ROUNDS {
+        let next = fold_constants(&inline(&expr));
+        if next == expr {
+            break;
+        }
+        expr = next;
+    }
+    expr
+}
+
+/// Folds operations on constants, prunes `if`s with a constant condition
+/// and simplifies algebraic identities.
+pub fn fold_constants(expr: &Expr) -> Expr {
+    ConstantFolder.fold_expr(expr.clone())
+}
+
+struct ConstantFolder;
+
+impl Fold for ConstantFolder {
+    fn fold_binop(&mut self, op: BinOp, exp1: Expr, exp2: Expr) -> Expr {
+        let exp1 = self.fold_expr(exp1);
+        binop(op, exp1, self.fold_expr(exp2))
+    }
+
+    fn fold_compare(&mut self, first: Expr, rest: Vec<(BinOp, Expr)>) -> Expr {
+        let first = self.fold_expr(first);
+        let rest = rest
+            .into_iter()
+            .map(|(op, exp)| (op, self.fold_expr(exp)))
+            .collect();
+        compare(first, rest)
+    }
+
+    fn fold_unaryop(&mut self, op: UnOp, exp: Expr) -> Expr {
+        unaryop(op, self.fold_expr(exp))
+    }
+
+    fn fold_if(&mut self, cond: Expr, exp1: Expr, exp2: Expr) -> Expr {
+        match (
+            self.fold_expr(cond),
+            self.fold_expr(exp1),
+            self.fold_expr(exp2),
+        ) {
+            (Expr::Bool(true), exp1, _) => exp1,
+            (Expr::Bool(false), _, exp2) => exp2,
+            (Expr::UnaryOp(UnOp::Not, cond), exp1, exp2) => {
+                Expr::If(cond, exp2.into(), exp1.into())
+            }
+            (cond, exp1, exp2) => Expr::if_expr(cond, exp1, exp2),
+        }
+    }
+}
+
+fn binop(op: BinOp, exp1: Expr, exp2: Expr) -> Expr {
+    if let (Some(v1), Some(v2)) = (constant(&exp1), constant(&exp2)) {
+        // An operation that fails, like a division by zero, is left to fail
+        // at run time.
+        if let Ok(val) = op.apply(v1, v2) {
+            return literal(val);
+        }
+    }
+    match (op, exp1, exp2) {
+        (BinOp::Add, Expr::Int(0), exp)
+        | (BinOp::Add | BinOp::Sub, exp, Expr::Int(0))
+        | (BinOp::Mul, Expr::Int(1), exp)
+        | (BinOp::Mul | BinOp::Div, exp, Expr::Int(1))
+        | (BinOp::And, Expr::Bool(true), exp)
+        | (BinOp::And, exp, Expr::Bool(true))
+        | (BinOp::Or, Expr::Bool(false), exp)
+        | (BinOp::Or, exp, Expr::Bool(false)) => exp,
+        // Both operands are evaluated, so the other one must not fail.
+        (BinOp::Mul, Expr::Int(0), exp) | (BinOp::Mul, exp, Expr::Int(0)) if is_pure(&exp) => {
+            Expr::Int(0)
+        }
+        (BinOp::And, Expr::Bool(false), exp) | (BinOp::And, exp, Expr::Bool(false))
+            if is_pure(&exp) =>
+        {
+            Expr::Bool(false)
+        }
+        (BinOp::Or, Expr::Bool(true), exp) | (BinOp::Or, exp, Expr::Bool(true))
+            if is_pure(&exp) =>
+        {
+            Expr::Bool(true)
+        }
+        (op, exp1, exp2) => Expr::binop(op, exp1, exp2),
+    }
+}
+
+fn compare(first: Expr, rest: Vec<(BinOp, Expr)>) -> Expr {
+    let constants = rest
+        .iter()
+        .map(|(_, exp)| constant(exp))
+        .collect::<Option<Vec<_>>>();
+    if let (Some(mut lhs), Some(constants)) = (constant(&first), constants) {
+        for ((op, _), rhs) in rest.iter().zip(constants) {
+            if op.apply(lhs, rhs.clone()).ok() != Some(Value::Bool(true)) {
+                return Expr::Bool(false);
+            }
+            lhs = rhs;
+        }
+        return Expr::Bool(true);
+    }
+    Expr::compare(first, rest)
+}
+
+fn unaryop(op: UnOp, exp: Expr) -> Expr {
+    if let Some(Ok(val)) = constant(&exp).map(|v| op.apply(v)) {
+        return literal(val);
+    }
+    match (op, exp) {
+        (UnOp::Neg, Expr::UnaryOp(UnOp::Neg, exp)) | (UnOp::Not, Expr::UnaryOp(UnOp::Not, exp)) => {
+            *exp
+        }
+        (op, exp) => Expr::unaryop(op, exp),
+    }
+}
+
+fn constant(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Int(v) => Some(Value::Int(*v)),
+        Expr::Bool(v) => Some(Value::Bool(*v)),
+        _ => None,
+    }
+}
+
+fn literal(val: Value) -> Expr {
+    match val {
+        Value::Int(v) => Expr::Int(v),
+        Value::Bool(v) => Expr::Bool(v),
+        _ => unreachable!("operators return ints and bools"),
+    }
+}
+
+/// Whether evaluating `expr` can neither fail nor loop, so that it can be
+/// dropped when its value is not used.
+pub fn is_pure(expr: &Expr) -> bool {
+    match expr {
+        Expr::Int(_) | Expr::Bool(_) | Expr::Variable(_) | Expr::Lambda(..) => true,
+        Expr::Program(prog, ret) => {
+            prog.iter().all(|stmt| match stmt {
+                Expr::Assign(_, _, exp) => is_pure(exp),
+                stmt => is_pure(stmt),
+            }) && is_pure(ret)
+        }
+        Expr::BinOp(BinOp::Div, exp1, exp2) => {
+            is_pure(exp1) && matches!(exp2.as_ref(), Expr::Int(v) if *v != 0)
+        }
+        Expr::BinOp(_, exp1, exp2) | Expr::Compose(exp1, exp2) => is_pure(exp1) && is_pure(exp2),
+        Expr::Compare(first, rest) => is_pure(first) && rest.iter().all(|(_, exp)| is_pure(exp)),
+        Expr::UnaryOp(_, exp) => is_pure(exp),
+        Expr::If(cond, exp1, exp2) => is_pure(cond) && is_pure(exp1) && is_pure(exp2),
+        // A `let` out of statement position binds a name for what follows.
+        Expr::Assign(..) | Expr::App(..) => false,
+    }
+}
+
+/// Beta reduces functions applied to an argument, inlines small functions,
+/// literals and variables bound by `let`s, and removes the `let`s of unused
+/// pure values.
+pub fn inline(expr: &Expr) -> Expr {
+    Inliner.fold_expr(expr.clone())
+}
+
+struct Inliner;
+
+impl Fold for Inliner {
+    fn fold_program(&mut self, prog: Vec<Expr>, ret: Expr) -> Expr {
+        let prog = prog.into_iter().map(|stmt| self.fold_expr(stmt)).collect();
+        program(prog, self.fold_expr(ret))
+    }
+
+    fn fold_app(&mut self, fun: Expr, arg: Expr) -> Expr {
+        let fun = self.fold_expr(fun);
+        beta(fun, self.fold_expr(arg))
+    }
+}
+
+/// Inlines what can be of the `let`s in a program whose statements are
+/// already inlined, then removes its dead `let`s.
+fn program(mut prog: Vec<Expr>, mut ret: Expr) -> Expr {
+    for i in 0..prog.len() {
+        let Expr::Assign(name, _, exp) = &prog[i] else {
+            continue;
+        };
+        let inlined = match exp.as_ref() {
+            Expr::Int(_) | Expr::Bool(_) => true,
+            Expr::Variable(var) => var != name,
+            // A recursive function would be inlined forever.
+            fun @ Expr::Lambda(..) => !fun.free_vars().contains(name) && size(fun) <= INLINE_SIZE,
+            _ => false,
+        };
+        if !inlined {
+            continue;
+        }
+        let rest = Expr::program(prog[i + 1..].to_vec(), ret.clone());
+        if let Some(rest) = substitute(&rest, *name, exp) {
+            // Reduces the applications of an inlined function.
+            let (tail, tail_ret) = match inline(&rest) {
+                Expr::Program(tail, tail_ret) => (tail, *tail_ret),
+                tail_ret => (vec![], tail_ret),
+            };
+            prog.truncate(i + 1);
+            prog.extend(tail);
+            ret = tail_ret;
+            break;
+        }
+    }
+    for i in (0..prog.len()).rev() {
+        let dead = match &prog[i] {
+            Expr::Assign(name, _, exp) => {
+                is_pure(exp)
+                    && !Expr::program(prog[i + 1..].to_vec(), ret.clone())
+                        .free_vars()
+                        .contains(name)
+            }
+            stmt => is_pure(stmt),
+        };
+        if dead {
+            prog.remove(i);
+        }
+    }
+    if prog.is_empty() {
+        return ret;
+    }
+    Expr::program(prog, ret)
+}
+
+/// Reduces `fun(arg)` when `fun` is a function: a variable or literal
+/// argument is substituted for the parameter, and any other is bound to it
+/// by a `let`, which keeps it evaluated once and before the body.
+fn beta(fun: Expr, arg: Expr) -> Expr {
+    let Expr::Lambda(param, ty, body) = fun else {
+        return Expr::app(fun, arg);
+    };
+    if matches!(arg, Expr::Int(_) | Expr::Bool(_) | Expr::Variable(_)) {
+        if let Some(body) = substitute(&body, param, &arg) {
+            return body;
+        }
+    }
+    // A function bound by a `let` would see itself under the parameter's
+    // name instead of the variable it refers to.
+    if matches!(arg, Expr::Lambda(..)) && arg.free_vars().contains(&param) {
+        return Expr::app(Expr::Lambda(param, ty, body), arg);
+    }
+    let binding = Expr::assign(param, ty, arg);
+    match *body {
+        Expr::Program(mut prog, ret) => {
+            prog.insert(0, binding);
+            program(prog, *ret)
+        }
+        body => program(vec![binding], body),
+    }
+}
+
+/// `expr` with the free occurrences of `name` replaced by `by`, or `None`
+/// if a binding in `expr` would capture a free variable of `by`.
+fn substitute(expr: &Expr, name: Ident, by: &Expr) -> Option<Expr> {
+    Substitution {
+        name,
+        by,
+        free: by.free_vars(),
+    }
+    .expr(expr)
+}
+
+struct Substitution<'a> {
+    name: Ident,
+    by: &'a Expr,
+    free: Vec<Ident>,
+}
+
+impl Substitution<'_> {
+    fn expr(&self, expr: &Expr) -> Option<Expr> {
+        Some(match expr {
+            Expr::Variable(name) if *name == self.name => self.by.clone(),
+            Expr::Int(_) | Expr::Bool(_) | Expr::Variable(_) => expr.clone(),
+            Expr::Program(prog, ret) => return self.program(prog, ret),
+            Expr::BinOp(op, exp1, exp2) => Expr::binop(*op, self.expr(exp1)?, self.expr(exp2)?),
+            Expr::Compare(first, rest) => Expr::compare(
+                self.expr(first)?,
+                rest.iter()
+                    .map(|(op, exp)| Some((*op, self.expr(exp)?)))
+                    .collect::<Option<_>>()?,
+            ),
+            Expr::UnaryOp(op, exp) => Expr::unaryop(*op, self.expr(exp)?),
+            Expr::If(cond, exp1, exp2) => {
+                Expr::if_expr(self.expr(cond)?, self.expr(exp1)?, self.expr(exp2)?)
+            }
+            Expr::Assign(name, ty, exp) => Expr::assign(*name, ty.clone(), self.expr(exp)?),
+            Expr::Lambda(param, ty, body) => {
+                if *param == self.name || !body.free_vars().contains(&self.name) {
+                    expr.clone()
+                } else if self.free.contains(param) {
+                    return None;
+                } else {
+                    Expr::lambda(*param, ty.clone(), self.expr(body)?)
+                }
+            }
+            Expr::App(fun, arg) => Expr::app(self.expr(fun)?, self.expr(arg)?),
+            Expr::Compose(first, then) => Expr::compose(self.expr(first)?, self.expr(then)?),
+        })
+    }
+
+    fn program(&self, prog: &[Expr], ret: &Expr) -> Option<Expr> {
+        let mut out = vec![];
+        for (i, stmt) in prog.iter().enumerate() {
+            let Expr::Assign(name, ty, exp) = stmt else {
+                out.push(self.expr(stmt)?);
+                continue;
+            };
+            let exp = match exp.as_ref() {
+                // A function refers to itself by its name.
+                Expr::Lambda(..) if *name == self.name => exp.as_ref().clone(),
+                Expr::Lambda(..) if self.free.contains(name) => {
+                    if exp.free_vars().contains(&self.name) {
+                        return None;
+                    }
+                    exp.as_ref().clone()
+                }
+                exp => self.expr(exp)?,
+            };
+            out.push(Expr::assign(*name, ty.clone(), exp));
+            let rest = || Expr::program(prog[i + 1..].to_vec(), ret.clone());
+            if *name == self.name {
+                let Expr::Program(tail, ret) = rest() else {
+                    unreachable!();
+                };
+                out.extend(tail);
+                return Some(Expr::Program(out, ret));
+            }
+            if self.free.contains(name) && rest().free_vars().contains(&self.name) {
+                return None;
+            }
+        }
+        Some(Expr::program(out, self.expr(ret)?))
+    }
+}
+
+/// The number of nodes in `expr`.
+fn size(expr: &Expr) -> usize {
+    let mut size = Size(0);
+    size.visit_expr(expr);
+    size.0
+}
+
+struct Size(usize);
+
+impl Visitor for Size {
+    fn visit_expr(&mut self, expr: &Expr) {
+        self.0 += 1;
+        visit::visit_expr(self, expr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        corpus::{FAILING, PROGRAMS},
+        desugar::parse_core,
+        eval::Eval,
+        types::TypeInfer,
+    };
+
+    use super::{fold_constants, optimize};
+
+    fn fold(src: &str) -> String {
+        fold_constants(&parse_core(src).unwrap()).to_string()
+    }
+
+    #[test]
+    fn fold_constants_and_identities() {
+        assert_eq!(fold("1 + 2 * 3"), " Int(7)");
+        assert_eq!(fold("if (1 < 2 <= 2) { 1 } else { 2 }"), " Int(1)");
+        assert_eq!(fold("if (!(1 > 2)) { 1 } else { 2 }"), " Int(1)");
+        assert_eq!(
+            fold("let x = 4; (x + 0) * 1 - -(-x)"),
+            "let x: ? = Int(4); (x - x)"
+        );
+        assert_eq!(
+            fold("let b = true; !(!b) && true || false"),
+            "let b: ? = true; b"
+        );
+        assert_eq!(
+            fold("let x = 4; x * 0 + 2 * 3"),
+            "let x: ? = Int(4); Int(6)"
+        );
+        assert_eq!(
+            fold("let b = true; if (!b) { 1 } else { 2 }"),
+            "let b: ? = true; if (b) { Int(2) } else { Int(1) }"
+        );
+        assert_eq!(
+            fold("lambda (x: int) { 9223372036854775807 + 1 + x }"),
+            " lambda (x:int) {  (Int(-9223372036854775808) + x) }"
+        );
+    }
+
+    #[test]
+    fn fold_constants_preserves_errors() {
+        assert_eq!(fold("1 / 0"), " (Int(1) / Int(0))");
+        assert_eq!(
+            fold("let zero = 0; 1 / zero * 0"),
+            "let zero: ? = Int(0); ((Int(1) / zero) * Int(0))"
+        );
+        for src in [
+            "1 / 0",
+            "let zero = 0; false && 1 / zero == 1",
+            "let zero = 0; (1 / zero) * 0",
+            "let f = lambda (x: int) { x * 0 }; f(3) + 2 * 3 - 1 / 1",
+            "let x = 5; 0 <= x - 5 < 10 - 0",
+        ] {
+            let expr = parse_core(src).unwrap();
+            TypeInfer::new().infer_type(&expr).unwrap();
+            let folded = fold_constants(&expr);
+            let show = |res: anyhow::Result<_>| res.map_err(|err| err.to_string());
+            assert_eq!(
+                show(Eval::new().eval(&folded)),
+                show(Eval::new().eval(&expr)),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn inline_and_remove_dead_bindings() {
+        let opt = |src: &str| optimize(&parse_core(src).unwrap()).to_string();
+        assert_eq!(
+            opt("let unused = 1 + 2; let inc = lambda (x: int) { x + 1 }; inc(inc(3))"),
+            "Int(5)"
+        );
+        assert_eq!(
+            opt("let sq = lambda (x: int) { x * x }; lambda (n: int) { sq(n + 1) }"),
+            "lambda (n:int) { let x: int = (n + Int(1)); (x * x) }"
+        );
+        // `f` is inlined once the `k` that would capture its own is gone.
+        assert_eq!(
+            opt("lambda (k: int) { let f = lambda (u: int) { k }; let k = 2; f(0) + k }"),
+            "lambda (k:int) { (k + Int(2)) }"
+        );
+        // Recursive functions and calls that may fail stay.
+        assert_eq!(
+            opt("let f = lambda (n: int) { if (n < 1) { 0 } else { f(n - 1) } }; let z = 0; let u = f(1 / z); 1"),
+            "let f: ? = lambda (n:int) { if ((n < Int(1))) { Int(0) } else { f((n - Int(1))) } }; \
+             let u: ? = f((Int(1) / Int(0))); Int(1)"
+        );
+    }
+
+    #[test]
+    fn optimize_preserves_results() {
+        for src in PROGRAMS.iter().chain(FAILING) {
+            let expr = parse_core(src).unwrap();
+            TypeInfer::new().infer_type(&expr).unwrap();
+            let show = |expr| match Eval::new().eval(expr) {
+                Ok(val) => val.to_string(),
+                Err(err) => err.to_string(),
+            };
+            assert_eq!(show(&optimize(&expr)), show(&expr), "{src}");
+        }
+    }
+}
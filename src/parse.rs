@@ -1,13 +1,77 @@
 use crate::{
-    expression::Expr,
+    ident::Ident,
+    operator::{Assoc, BinOp, UnOp},
+    span::Span,
+    syntax::{Operator, Syntax, SyntaxKind},
     tokenize::{Token, Tokenizer},
     types::Type,
 };
 
+use std::collections::HashMap;
+
 use anyhow::{bail, Ok, Result};
 
 pub struct Parser {
-    tokens: Vec<Token>,
+    /// The tokens yet to be read, the next one last.
+    tokens: Vec<(Token, Span)>,
+    /// The end of the last token read.
+    end: usize,
+    operators: HashMap<String, Fixity>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fixity {
+    prec: u8,
+    assoc: Assoc,
+    kind: Operator,
+}
+
+impl Fixity {
+    fn builtin(op: BinOp) -> Self {
+        let info = op.info();
+        Self::new(info.prec, info.assoc, Operator::Builtin(op))
+    }
+
+    fn new(prec: u8, assoc: Assoc, kind: Operator) -> Self {
+        Self { prec, assoc, kind }
+    }
+
+    /// The lowest precedence allowed in the right operand.
+    fn rhs_prec(&self) -> u8 {
+        match self.assoc {
+            Assoc::Right => self.prec,
+            Assoc::Left | Assoc::None | Assoc::Chain => self.prec + 1,
+        }
+    }
+}
+
+/// The name a user-declared operator's function is bound to: `$op` and the
+/// bytes of the symbol in hex, so no variable of the script can shadow it
+/// and the backends can still use it as a name.
+fn operator_binding(op: &str) -> Ident {
+    let hex: String = op.bytes().map(|b| format!("{b:02x}")).collect();
+    Ident::intern(&format!("$op{hex}"))
+}
+
+/// Symbols with a fixed meaning in the grammar that cannot become operators.
+const RESERVED_SYMBOLS: [&str; 5] = [";", ":", "=", "->", "!"];
+
+fn builtin_operators() -> HashMap<String, Fixity> {
+    BinOp::ALL
+        .into_iter()
+        .map(|op| (op.symbol().to_owned(), Fixity::builtin(op)))
+        .chain([
+            ("|>".to_owned(), Fixity::new(0, Assoc::Left, Operator::Pipe)),
+            (
+                ">>".to_owned(),
+                Fixity::new(9, Assoc::Left, Operator::Compose { flipped: false }),
+            ),
+            (
+                "<<".to_owned(),
+                Fixity::new(9, Assoc::Left, Operator::Compose { flipped: true }),
+            ),
+        ])
+        .collect()
 }
 
 macro_rules! sym {
@@ -24,44 +88,61 @@ macro_rules! kwd {
 impl Parser {
     pub fn new(input: &str) -> Self {
         Self {
-            tokens: Tokenizer::new(input)
-                .tokenize()
-                .iter()
-                .rev()
-                .cloned()
-                .collect(),
+            tokens: Tokenizer::new(input).tokenize().into_iter().rev().collect(),
+            end: 0,
+            operators: builtin_operators(),
         }
     }
 
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.last().map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let (token, span) = self.tokens.pop()?;
+        self.end = span.end;
+        Some(token)
+    }
+
+    /// Where the next token starts.
+    fn start(&self) -> usize {
+        self.tokens.last().map_or(self.end, |(_, span)| span.start)
+    }
+
+    /// The span of the next token, or the end of the input.
+    fn here(&self) -> Span {
+        self.tokens
+            .last()
+            .map_or(Span::new(self.end, self.end), |(_, span)| *span)
+    }
+
+    /// A node running from `start` to the end of the last token read.
+    fn node(&self, kind: SyntaxKind, start: usize) -> Syntax {
+        Syntax::new(kind, Span::new(start, self.end))
+    }
+
     fn consume(&mut self, token: Token) -> bool {
-        if let Some(t) = self.tokens.last() {
-            if *t == token {
-                let _ = self.tokens.pop();
-                true
-            } else {
-                false
-            }
+        if self.peek() == Some(&token) {
+            let _ = self.next();
+            true
         } else {
             false
         }
     }
 
     fn expect(&mut self, token: Token) -> Result<()> {
-        if let Some(t) = self.tokens.pop() {
-            if t != token {
-                bail!("unexpected token: {:?}", t)
-            } else {
-                Ok(())
-            }
-        } else {
-            bail!("unexpected EOF")
+        let span = self.here();
+        match self.next() {
+            Some(t) if t == token => Ok(()),
+            Some(t) => bail!("unexpected token: {:?} at {span}", t),
+            None => bail!("unexpected EOF"),
         }
     }
 
     fn consume_int(&mut self) -> Option<i64> {
-        if let Some(Token::Int(val)) = self.tokens.last() {
+        if let Some(Token::Int(val)) = self.peek() {
             let r = Some(*val);
-            let _ = self.tokens.pop();
+            let _ = self.next();
             r
         } else {
             None
@@ -69,12 +150,12 @@ impl Parser {
     }
 
     fn consume_bool(&mut self) -> Option<bool> {
-        if let Some(Token::Keyword(val)) = self.tokens.last() {
+        if let Some(Token::Keyword(val)) = self.peek() {
             if *val == "true" {
-                let _ = self.tokens.pop();
+                let _ = self.next();
                 Some(true)
             } else if *val == "false" {
-                let _ = self.tokens.pop();
+                let _ = self.next();
                 Some(false)
             } else {
                 None
@@ -84,29 +165,31 @@ impl Parser {
         }
     }
 
-    fn consume_ident(&mut self) -> Option<String> {
-        if let Some(Token::Ident(val)) = self.tokens.last() {
-            let r = Some(val.clone());
-            let _ = self.tokens.pop();
+    fn consume_ident(&mut self) -> Option<Ident> {
+        if let Some(Token::Ident(val)) = self.peek() {
+            let r = Some(*val);
+            let _ = self.next();
             r
         } else {
             None
         }
     }
 
-    fn expect_ident(&mut self) -> Result<String> {
-        if let Some(Token::Ident(val)) = self.tokens.pop() {
+    fn expect_ident(&mut self) -> Result<Ident> {
+        let span = self.here();
+        if let Some(Token::Ident(val)) = self.next() {
             Ok(val)
         } else {
-            bail!("unexpected non-identifier")
+            bail!("unexpected non-identifier at {span}")
         }
     }
 
-    fn expr(&mut self) -> Result<Expr> {
+    fn expr(&mut self) -> Result<Syntax> {
         self.parse_if()
     }
 
-    fn primary(&mut self) -> Result<Expr> {
+    fn primary(&mut self) -> Result<Syntax> {
+        let start = self.start();
         if self.consume(kwd!("lambda")) {
             self.expect(sym!("("))?;
             let ident = self.expect_ident()?;
@@ -117,231 +200,273 @@ impl Parser {
             };
             self.expect(sym!(")"))?;
             self.expect(sym!("{"))?;
-            let prog = self.prog()?;
+            // Operators declared in the body are local to it.
+            let operators = self.operators.clone();
+            let prog = self.block();
+            self.operators = operators;
+            let prog = prog?;
             self.expect(sym!("}"))?;
-            Ok(Expr::lambda(ident, ty, prog))
+            Ok(self.node(SyntaxKind::Lambda(ident, ty, Box::new(prog)), start))
         } else if self.consume(sym!("(")) {
-            let exp = self.expr();
-            self.expect(sym!(")"))?;
-            exp
+            self.paren(start)
         } else if let Some(num) = self.consume_int() {
-            Ok(Expr::int(num))
+            Ok(self.node(SyntaxKind::Int(num), start))
         } else if let Some(b) = self.consume_bool() {
-            Ok(Expr::boolean(b))
+            Ok(self.node(SyntaxKind::Bool(b), start))
         } else if let Some(name) = self.consume_ident() {
-            Ok(Expr::variable(name))
+            Ok(self.node(SyntaxKind::Variable(name), start))
         } else {
-            bail!("unexpected token: {:?}", self.tokens.last())
+            bail!("unexpected token: {:?} at {}", self.peek(), self.here())
         }
     }
 
-    fn parse_if(&mut self) -> Result<Expr> {
+    /// The rest of a parenthesised expression or operator section: `(+)`,
+    /// `(* 2)` and `(10 -)` are functions of their missing operands. As a
+    /// prefix, `-` is negation, so `(- 2)` is the number `-2`.
+    fn paren(&mut self, start: usize) -> Result<Syntax> {
+        if let Some((op, fixity)) = self.peek_operator() {
+            if UnOp::from_symbol(&op).is_none() || self.operator_ends_section() {
+                let _ = self.next();
+                let rhs = if self.consume(sym!(")")) {
+                    None
+                } else {
+                    let rhs = self.rhs(&op, &fixity)?;
+                    self.expect(sym!(")"))?;
+                    Some(Box::new(rhs))
+                };
+                return Ok(self.node(SyntaxKind::Section(fixity.kind, None, rhs), start));
+            }
+        }
+
+        let exp = self.expr()?;
+        if let Some((_, fixity)) = self.peek_operator() {
+            if self.operator_ends_section() {
+                let _ = self.next();
+                self.expect(sym!(")"))?;
+                let section = SyntaxKind::Section(fixity.kind, Some(Box::new(exp)), None);
+                return Ok(self.node(section, start));
+            }
+        }
+        self.expect(sym!(")"))?;
+        Ok(exp)
+    }
+
+    /// Whether the operator about to be read is directly followed by `)`,
+    /// as in the section `(10 -)`.
+    fn operator_ends_section(&self) -> bool {
+        self.tokens.len() >= 2 && self.tokens[self.tokens.len() - 2].0 == sym!(")")
+    }
+
+    fn parse_if(&mut self) -> Result<Syntax> {
+        let start = self.start();
         if self.consume(kwd!("if")) {
             self.expect(sym!("("))?;
-            let cond = self.or()?;
+            let cond = self.binary(0)?;
             self.expect(sym!(")"))?;
             self.expect(sym!("{"))?;
-            let exp1 = self.or()?;
+            let exp1 = self.binary(0)?;
             self.expect(sym!("}"))?;
             self.expect(kwd!("else"))?;
             self.expect(sym!("{"))?;
-            let exp2 = self.or()?;
+            let exp2 = self.binary(0)?;
             self.expect(sym!("}"))?;
-            Ok(Expr::if_expr(cond, exp1, exp2))
+            let kind = SyntaxKind::If(Box::new(cond), Box::new(exp1), Box::new(exp2));
+            Ok(self.node(kind, start))
         } else {
-            self.or()
+            self.binary(0)
         }
     }
 
-    fn or(&mut self) -> Result<Expr> {
-        let mut ret = self.and()?;
-        loop {
-            if self.consume(sym!("||")) {
-                let exp = self.and()?;
-                ret = Expr::binop("||".into(), ret, exp);
-            } else {
-                return Ok(ret);
-            }
+    fn peek_operator(&self) -> Option<(String, Fixity)> {
+        if let Some(Token::Symbol(op)) = self.peek() {
+            self.operators
+                .get(op)
+                .map(|fixity| (op.clone(), fixity.clone()))
+        } else {
+            None
         }
     }
 
-    fn and(&mut self) -> Result<Expr> {
-        let mut ret = self.equ()?;
-        loop {
-            if self.consume(sym!("&&")) {
-                let exp = self.equ()?;
-                ret = Expr::binop("&&".into(), ret, exp);
-            } else {
-                return Ok(ret);
-            }
-        }
+    /// Precedence climbing over the operator table: parses a chain of
+    /// operators whose precedence is at least `min_prec`.
+    fn binary(&mut self, min_prec: u8) -> Result<Syntax> {
+        self.binary_in(min_prec, None)
     }
 
-    fn equ(&mut self) -> Result<Expr> {
-        let mut ret = self.rel()?;
-        let mut now;
-        let mut prev;
+    /// The right operand of `op`.
+    fn rhs(&mut self, op: &str, fixity: &Fixity) -> Result<Syntax> {
+        let comparison = (fixity.assoc == Assoc::Chain).then_some(op);
+        self.binary_in(fixity.rhs_prec(), comparison)
+    }
 
-        if self.consume(sym!("==".to_owned())) {
-            now = self.rel()?.clone();
-            ret = Expr::binop("==".to_owned(), ret, now.clone());
-        } else if self.consume(sym!("!=".to_owned())) {
-            now = self.rel()?.clone();
-            ret = Expr::binop("!=".into(), ret, now.clone());
-        } else {
-            return Ok(ret);
-        }
+    /// Parses operators like `binary`, as the right operand of the
+    /// `comparison` if there is one. Comparisons of different precedence
+    /// compare a `bool` with an `int`, so `a < b == c` and `a == b < c`
+    /// need parentheses.
+    fn binary_in(&mut self, min_prec: u8, comparison: Option<&str>) -> Result<Syntax> {
+        let mut ret = self.unary()?;
+        let mut last: Option<(String, Fixity)> = None;
 
-        loop {
-            if self.consume(sym!("==")) {
-                prev = now;
-                now = self.rel()?.clone();
-                ret = Expr::binop(
-                    "==".into(),
-                    ret,
-                    Expr::binop("==".into(), prev.clone(), now.clone()),
-                );
-            } else if self.consume(sym!("!=".to_owned())) {
-                prev = now;
-                now = self.rel()?.clone();
-                ret = Expr::binop(
-                    "!=".into(),
-                    ret,
-                    Expr::binop("!=".into(), prev.clone(), now.clone()),
-                );
-            } else {
-                return Ok(ret);
+        while let Some((op, fixity)) = self.peek_operator() {
+            if fixity.prec < min_prec || self.operator_ends_section() {
+                break;
             }
+            let mixed = match (&last, comparison) {
+                (Some((last_op, last_fixity)), _) if last_fixity.prec == fixity.prec => {
+                    (fixity.assoc == Assoc::None || fixity.assoc != last_fixity.assoc)
+                        .then_some(last_op.as_str())
+                }
+                (Some((last_op, last_fixity)), _) if last_fixity.assoc == Assoc::Chain => {
+                    (fixity.assoc == Assoc::Chain).then_some(last_op.as_str())
+                }
+                (_, Some(outer)) => (fixity.assoc == Assoc::Chain).then_some(outer),
+                _ => None,
+            };
+            if let Some(other) = mixed {
+                bail!(
+                    "operators {other} and {op} cannot be mixed without parentheses at {}",
+                    self.here()
+                )
+            }
+            let chained = matches!(&last, Some((_, last_fixity)) if last_fixity.prec == fixity.prec)
+                && fixity.assoc == Assoc::Chain;
+            let _ = self.next();
+            let now = self.rhs(&op, &fixity)?;
+
+            let start = ret.span.start;
+            let kind = match fixity.kind {
+                Operator::Builtin(op) if chained => Self::extend_chain(ret, op, now),
+                kind => SyntaxKind::Binary(kind, Box::new(ret), Box::new(now)),
+            };
+            ret = self.node(kind, start);
+            last = Some((op, fixity));
         }
+        Ok(ret)
     }
 
-    fn rel(&mut self) -> Result<Expr> {
-        let mut ret = self.add()?;
-        let mut now;
-        let mut prev;
-
-        if self.consume(sym!("<")) {
-            now = self.add()?.clone();
-            ret = Expr::binop("<".into(), ret, now.clone());
-        } else if self.consume(sym!(">")) {
-            now = self.add()?.clone();
-            ret = Expr::binop(">".into(), ret, now.clone());
-        } else if self.consume(sym!("<=")) {
-            now = self.add()?.clone();
-            ret = Expr::binop("<=".into(), ret, now.clone());
-        } else if self.consume(sym!(">=")) {
-            now = self.add()?.clone();
-            ret = Expr::binop(">=".into(), ret, now.clone());
-        } else {
-            return Ok(ret);
+    /// `a < b` followed by `< c` becomes the comparison chain `a < b < c`.
+    fn extend_chain(cmp: Syntax, op: BinOp, rhs: Syntax) -> SyntaxKind {
+        match cmp.kind {
+            SyntaxKind::Compare(first, mut rest) => {
+                rest.push((op, rhs));
+                SyntaxKind::Compare(first, rest)
+            }
+            SyntaxKind::Binary(Operator::Builtin(prev_op), lhs, mid) => {
+                SyntaxKind::Compare(lhs, vec![(prev_op, *mid), (op, rhs)])
+            }
+            _ => unreachable!("comparison chains start with a comparison"),
         }
+    }
 
-        loop {
-            if self.consume(sym!("<")) {
-                prev = now;
-                now = self.add()?.clone();
-                ret = Expr::binop(
-                    "<".into(),
-                    ret,
-                    Expr::binop("<".into(), prev.clone(), now.clone()),
-                );
-            } else if self.consume(sym!(">")) {
-                prev = now;
-                now = self.add()?.clone();
-                ret = Expr::binop(
-                    ">".into(),
-                    ret,
-                    Expr::binop(">".into(), prev.clone(), now.clone()),
-                );
-            } else if self.consume(sym!("<=")) {
-                prev = now;
-                now = self.add()?.clone();
-                ret = Expr::binop(
-                    "<=".into(),
-                    ret,
-                    Expr::binop("<=".into(), prev.clone(), now.clone()),
-                );
-            } else if self.consume(sym!(">=")) {
-                prev = now;
-                now = self.add()?.clone();
-                ret = Expr::binop(
-                    ">=".into(),
-                    ret,
-                    Expr::binop(">=".into(), prev.clone(), now.clone()),
-                );
-            } else {
-                return Ok(ret);
+    fn unary(&mut self) -> Result<Syntax> {
+        let start = self.start();
+        for op in UnOp::ALL {
+            if self.consume(sym!(op.symbol())) {
+                let exp = self.app()?;
+                return Ok(self.node(SyntaxKind::Unary(op, Box::new(exp)), start));
             }
         }
+        self.app()
     }
 
-    fn add(&mut self) -> Result<Expr> {
-        let mut ret = self.mul()?;
-        loop {
-            if self.consume(sym!("+")) {
-                let exp = self.mul()?;
-                ret = Expr::binop("+".to_owned(), ret, exp);
-            } else if self.consume(sym!("-")) {
-                let exp = self.mul()?;
-                ret = Expr::binop("-".to_owned(), ret, exp);
-            } else {
-                return Ok(ret);
-            }
+    fn app(&mut self) -> Result<Syntax> {
+        let start = self.start();
+        let mut ret = self.primary()?;
+        while self.consume(sym!("(")) {
+            let var = self.expr()?;
+            self.expect(sym!(")"))?;
+            ret = self.node(SyntaxKind::App(Box::new(ret), Box::new(var)), start);
         }
+        Ok(ret)
     }
 
-    fn mul(&mut self) -> Result<Expr> {
-        let mut ret = self.unary()?;
+    /// A whole script, which must use up all of the input.
+    pub fn program(&mut self) -> Result<Syntax> {
+        let prog = self.block()?;
+        if let Some(token) = self.peek() {
+            bail!("unexpected token: {:?} at {}", token, self.here())
+        }
+        Ok(prog)
+    }
+
+    /// `let`s and fixity declarations followed by the value of the block.
+    fn block(&mut self) -> Result<Syntax> {
+        let start = self.start();
+        let mut prog = vec![];
         loop {
-            if self.consume(sym!("*".to_owned())) {
-                let exp = self.unary()?;
-                ret = Expr::binop("*".to_owned(), ret, exp);
-            } else if self.consume(sym!("/".to_owned())) {
-                let exp = self.unary()?;
-                ret = Expr::binop("/".to_owned(), ret, exp);
+            let stmt = self.start();
+            if self.consume(kwd!("let")) {
+                let ident = self.expect_ident()?;
+                let ty = if self.consume(sym!(":")) {
+                    Some(self.parse_ty()?)
+                } else {
+                    None
+                };
+                self.expect(sym!("="))?;
+                let expr = self.expr()?;
+                self.expect(sym!(";"))?;
+                prog.push(self.node(SyntaxKind::Let(ident, ty, Box::new(expr)), stmt));
+            } else if let Some(assoc) = self.consume_fixity_keyword() {
+                prog.push(self.fixity_decl(assoc, stmt)?);
             } else {
-                return Ok(ret);
+                break;
             }
         }
+
+        let ret = self.expr()?;
+        Ok(self.node(SyntaxKind::Block(prog, Box::new(ret)), start))
     }
 
-    fn unary(&mut self) -> Result<Expr> {
-        if self.consume(sym!("-".to_owned())) {
-            Ok(Expr::unaryop("-".into(), self.app()?))
-        } else if self.consume(sym!("!".to_owned())) {
-            Ok(Expr::unaryop("!".into(), self.app()?))
+    fn consume_fixity_keyword(&mut self) -> Option<Assoc> {
+        if self.consume(kwd!("infixl")) {
+            Some(Assoc::Left)
+        } else if self.consume(kwd!("infixr")) {
+            Some(Assoc::Right)
+        } else if self.consume(kwd!("infix")) {
+            Some(Assoc::None)
         } else {
-            Ok(self.app()?)
+            None
         }
     }
 
-    fn app(&mut self) -> Result<Expr> {
-        let mut ret = self.primary()?;
-        while self.consume(sym!("(".to_owned())) {
-            let var = self.expr()?;
-            self.expect(sym!(")".to_owned()))?;
-            ret = Expr::app(ret, var)
+    /// `infixl 6 <+> = plus;` makes `a <+> b` parse as `plus(a)(b)` from
+    /// here to the end of the enclosing block. The declaration is a `let` of
+    /// `plus` to the hidden name of `<+>`, so `plus` is the function in scope
+    /// at the declaration, wherever the operator is used.
+    fn fixity_decl(&mut self, assoc: Assoc, start: usize) -> Result<Syntax> {
+        let prec = match self.consume_int() {
+            Some(prec @ 0..=9) => prec as u8,
+            Some(prec) => bail!("operator precedence must be between 0 and 9, got {prec}"),
+            None => bail!("expected operator precedence at {}", self.here()),
+        };
+        let span = self.here();
+        let op = match self.next() {
+            Some(Token::Symbol(op)) => op,
+            t => bail!("expected operator symbol, found {:?} at {span}", t),
+        };
+        if RESERVED_SYMBOLS.contains(&op.as_str()) {
+            bail!("{op} cannot be used as an operator")
         }
-        Ok(ret)
-    }
-
-    pub fn prog(&mut self) -> Result<Expr> {
-        let mut prog = vec![];
-        while self.consume(Token::Keyword("let".to_owned())) {
-            let ident = self.expect_ident()?;
-            let ty = if self.consume(sym!(":")) {
-                Some(self.parse_ty()?)
-            } else {
-                None
-            };
-            self.expect(sym!("="))?;
-            let expr = self.expr()?;
-            self.expect(sym!(";"))?;
-            prog.push(Expr::assign(ident, ty, expr));
+        if let Some(fixity) = self.operators.get(&op) {
+            if !matches!(fixity.kind, Operator::User(_)) {
+                bail!("builtin operator {op} cannot be redefined")
+            }
         }
-
-        let ret = self.expr()?;
-        Ok(Expr::program(prog, ret))
+        self.expect(sym!("="))?;
+        let fun_start = self.start();
+        let fun = self.expect_ident()?;
+        let fun = self.node(SyntaxKind::Variable(fun), fun_start);
+        self.expect(sym!(";"))?;
+        let name = operator_binding(&op);
+        self.operators.insert(
+            op,
+            Fixity {
+                prec,
+                assoc,
+                kind: Operator::User(name),
+            },
+        );
+        Ok(self.node(SyntaxKind::Let(name, None, Box::new(fun)), start))
     }
 
     // =====================================================================
@@ -351,19 +476,22 @@ impl Parser {
     }
 
     fn fntype(&mut self) -> Result<Type> {
-        let mut ret = self.primitive_type()?;
-        loop {
-            if self.consume(sym!("->")) {
-                let ty = self.primitive_type()?;
-                ret = Type::func(ty, ret);
-            } else {
-                return Ok(ret);
-            }
+        let ret = self.primitive_type()?;
+        if self.consume(sym!("->")) {
+            Ok(Type::func(ret, self.fntype()?))
+        } else {
+            Ok(ret)
         }
     }
 
     fn primitive_type(&mut self) -> Result<Type> {
-        if let Some(Token::Type(val)) = self.tokens.pop() {
+        if self.consume(sym!("(")) {
+            let ty = self.parse_ty()?;
+            self.expect(sym!(")"))?;
+            return Ok(ty);
+        }
+        let span = self.here();
+        if let Some(Token::Type(val)) = self.next() {
             if &val == "int" {
                 Ok(Type::Int)
             } else if &val == "bool" {
@@ -372,20 +500,247 @@ impl Parser {
                 bail!("unexpected type: {val}")
             }
         } else {
-            bail!("unexpected non-type")
+            bail!("unexpected non-type at {span}")
         }
     }
 }
 
 #[cfg(test)]
-mod parse {
-    use crate::expression::Expr;
+mod tests {
+    use crate::{
+        desugar::{desugar, parse_core},
+        expression::Expr,
+        operator::{BinOp, UnOp},
+        span::Span,
+        syntax::SyntaxKind,
+        types::{Type, TypeInfer},
+    };
 
-    use super::Parser;
+    use super::{operator_binding, Parser};
+
+    fn core(src: &str) -> Expr {
+        desugar(&Parser::new(src).expr().unwrap()).0
+    }
+
+    fn var(name: &str) -> Expr {
+        Expr::variable(name.into())
+    }
 
     #[test]
     fn parse_num() {
-        let expr: Expr = Parser::new("233425").expr().unwrap();
+        let expr = core("233425");
         assert_eq!(expr, Expr::Int(233425),);
     }
+
+    #[test]
+    fn parse_arrow_types() {
+        let ty = Parser::new("int -> int -> bool").parse_ty().unwrap();
+        assert_eq!(ty, Type::func(Type::Int, Type::func(Type::Int, Type::Bool)));
+        let check = |src| TypeInfer::new().infer_type(&parse_core(src).unwrap());
+        assert!(check("let f: int -> bool = lambda (n: int) { n < 2 }; f(1)").is_ok());
+        assert!(
+            check("let id = lambda (f: (int -> bool)) { f }; id(lambda (n: int) { n < 2 })")
+                .is_ok()
+        );
+        assert!(check("let f: int -> bool = lambda (n: int) { n + 2 }; f(1)").is_err());
+    }
+
+    #[test]
+    fn parse_precedence() {
+        let expr = core("1 + 2 * 3 - 4 == 3 || b && c");
+        let sum = Expr::binop(
+            BinOp::Sub,
+            Expr::binop(
+                BinOp::Add,
+                Expr::int(1),
+                Expr::binop(BinOp::Mul, Expr::int(2), Expr::int(3)),
+            ),
+            Expr::int(4),
+        );
+        assert_eq!(
+            expr,
+            Expr::binop(
+                BinOp::Or,
+                Expr::binop(BinOp::Eq, sum, Expr::int(3)),
+                Expr::binop(BinOp::And, var("b"), var("c")),
+            )
+        );
+    }
+
+    #[test]
+    fn parse_user_operator() {
+        let expr =
+            parse_core("infixr 5 <+> = plus; infixl 7 <*> = times; a <+> b <+> c <*> d").unwrap();
+        let (plus, times) = (operator_binding("<+>"), operator_binding("<*>"));
+        let call = |f, x, y| Expr::app(Expr::app(Expr::variable(f), x), y);
+        assert_eq!(
+            expr,
+            Expr::program(
+                vec![
+                    Expr::assign(plus, None, var("plus")),
+                    Expr::assign(times, None, var("times")),
+                ],
+                call(
+                    plus,
+                    var("a"),
+                    call(plus, var("b"), call(times, var("c"), var("d")))
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn user_operators_are_local_to_their_block() {
+        let src = "let f = lambda (a) { infixl 6 <+> = plus; a <+> a }; f(1)";
+        assert!(parse_core(src).is_ok());
+        let src = "let f = lambda (a) { infixl 6 <+> = plus; a }; a <+> b";
+        assert!(parse_core(src).is_err());
+        // An operator can be declared again inside a block and still has its
+        // outer meaning after it.
+        let expr = parse_core(
+            "infixl 6 <+> = plus; let f = lambda (a) { infixr 6 <+> = times; a <+> a }; a <+> b",
+        )
+        .unwrap();
+        let Expr::Program(prog, ret) = expr else {
+            panic!("{expr}");
+        };
+        let plus = Expr::variable(operator_binding("<+>"));
+        assert_eq!(*ret, Expr::app(Expr::app(plus, var("a")), var("b")));
+        assert!(prog[1].to_string().contains("times"));
+    }
+
+    #[test]
+    fn parse_non_associative_operator() {
+        assert!(parse_core("infix 4 === = eq; a === b").is_ok());
+        assert!(parse_core("infix 4 === = eq; a === b === c").is_err());
+        assert!(parse_core("infix 4 === = eq; a === b == c").is_err());
+        assert!(parse_core("infixl 6 + = plus; a").is_err());
+        assert!(parse_core("infixl 10 <+> = plus; a").is_err());
+    }
+
+    #[test]
+    fn parse_comparison_chain() {
+        let expr = core("a < b <= c > d");
+        assert_eq!(
+            expr,
+            Expr::compare(
+                var("a"),
+                vec![
+                    (BinOp::Lt, var("b")),
+                    (BinOp::Le, var("c")),
+                    (BinOp::Gt, var("d"))
+                ]
+            )
+        );
+        let expr = core("a == b != c");
+        assert_eq!(
+            expr,
+            Expr::compare(var("a"), vec![(BinOp::Eq, var("b")), (BinOp::Ne, var("c"))])
+        );
+    }
+
+    #[test]
+    fn parse_mixed_comparisons_need_parentheses() {
+        for src in [
+            "a < b == c < d",
+            "a < b == c",
+            "a == b < c",
+            "a != b + 1 >= c",
+            "(== a < b)",
+        ] {
+            let err = Parser::new(src).expr().unwrap_err().to_string();
+            assert!(
+                err.contains("cannot be mixed without parentheses"),
+                "{src}: {err}"
+            );
+        }
+        assert!(Parser::new("(a < b) == (c < d)").expr().is_ok());
+        assert!(Parser::new("a < b && c == d").expr().is_ok());
+    }
+
+    #[test]
+    fn parse_pipe_and_compose() {
+        let expr = core("x |> f >> g << h |> k");
+        assert_eq!(
+            expr,
+            Expr::app(
+                var("k"),
+                Expr::app(
+                    Expr::compose(var("h"), Expr::compose(var("f"), var("g"))),
+                    var("x")
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn parse_sections() {
+        let section = |param: &str, body| Expr::lambda(param.into(), None, body);
+        let add = |x, y| Expr::binop(BinOp::Add, x, y);
+        assert_eq!(
+            core("(+)"),
+            section("$lhs", section("$rhs", add(var("$lhs"), var("$rhs"))))
+        );
+        // An operand that takes work is evaluated once, not per call.
+        let bind = |name: &str, value, body| {
+            Expr::program(vec![Expr::assign(name.into(), None, value)], body)
+        };
+        assert_eq!(
+            core("(+ 2 * x)"),
+            bind(
+                "$rhs",
+                Expr::binop(BinOp::Mul, Expr::int(2), var("x")),
+                section("$lhs", add(var("$lhs"), var("$rhs")))
+            )
+        );
+        assert_eq!(
+            core("(1 + 2 -)"),
+            bind(
+                "$lhs",
+                add(Expr::int(1), Expr::int(2)),
+                section("$rhs", Expr::binop(BinOp::Sub, var("$lhs"), var("$rhs")))
+            )
+        );
+        assert_eq!(
+            core("(x -)"),
+            section("$rhs", Expr::binop(BinOp::Sub, var("x"), var("$rhs")))
+        );
+        assert_eq!(core("(- 2)"), Expr::unaryop(UnOp::Neg, Expr::int(2)));
+        assert!(Parser::new("(* 2 + 1)").expr().is_err());
+        assert!(Parser::new("f(1 +)").expr().is_err());
+    }
+
+    #[test]
+    fn parse_uses_all_input() {
+        for src in [
+            "let x = 3; x*-1",
+            "1--1",
+            "infixl 6 <+> = plus; a <+>-b",
+            "1 2",
+            "1; 2",
+        ] {
+            let err = Parser::new(src).program().unwrap_err().to_string();
+            assert!(err.starts_with("unexpected token"), "{src}: {err}");
+        }
+        let err = Parser::new("1 + 2 )").program().unwrap_err().to_string();
+        assert_eq!(err, "unexpected token: Symbol(\")\") at 6..7");
+    }
+
+    #[test]
+    fn parse_spans() {
+        let src = "let f = lambda (x) { x + 1 }; f(2) |> (* 3)";
+        let syntax = Parser::new(src).program().unwrap();
+        let at = |span: Span| &src[span.start..span.end];
+        assert_eq!(at(syntax.span), src);
+        let SyntaxKind::Block(stmts, ret) = &syntax.kind else {
+            panic!("expected a block, got {syntax:?}")
+        };
+        assert_eq!(at(stmts[0].span), "let f = lambda (x) { x + 1 };");
+        assert_eq!(at(ret.span), "f(2) |> (* 3)");
+        let SyntaxKind::Binary(_, lhs, rhs) = &ret.kind else {
+            panic!("expected a pipe, got {ret:?}")
+        };
+        assert_eq!(at(lhs.span), "f(2)");
+        assert_eq!(at(rhs.span), "(* 3)");
+    }
 }
@@ -0,0 +1,231 @@
+//! Name resolution: replaces every variable name with the frame slot or
+//! closure capture it refers to, so the evaluator never looks names up.
+//!
+//! Closures are flat, so a variable is either local to the innermost
+//! function (depth 0) or one of the values its closure captured; captures of
+//! captures are threaded through each enclosing function when it is created.
+
+use std::rc::Rc;
+
+use crate::{
+    expression::Expr,
+    ident::Ident,
+    operator::{BinOp, UnOp},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Var {
+    /// A slot in the frame of the current call. A function's parameter is
+    /// slot 0, and every `let` in its body gets a slot of its own.
+    Local(usize),
+    /// A value captured by the current closure.
+    Capture(usize),
+}
+
+/// Resolved code. Subtrees are shared, so a suspended evaluation can hold
+/// on to the code it has yet to run.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Code {
+    Int(i64),
+    Bool(bool),
+    Var(Var),
+    /// A name bound nowhere; evaluating it is an error.
+    Unbound(Ident),
+    Program(Vec<Rc<Code>>, Rc<Code>),
+    Let(usize, Rc<Code>),
+    BinOp(BinOp, Rc<Code>, Rc<Code>),
+    Compare(Rc<Code>, Vec<(BinOp, Rc<Code>)>),
+    UnaryOp(UnOp, Rc<Code>),
+    If(Rc<Code>, Rc<Code>, Rc<Code>),
+    Lambda(Rc<Function>),
+    App(Rc<Code>, Rc<Code>),
+    Compose(Rc<Code>, Rc<Code>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Function {
+    pub param: Ident,
+    /// The variables of the defining scope captured by the closure, in
+    /// capture order.
+    pub captures: Vec<(Ident, Var)>,
+    /// The capture through which a `let`-bound function refers to itself;
+    /// it is filled in with the closure once that has been created.
+    pub recursive: Option<usize>,
+    pub frame_size: usize,
+    pub body: Rc<Code>,
+}
+
+/// A resolved top-level program.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Program {
+    pub frame_size: usize,
+    pub code: Rc<Code>,
+}
+
+#[derive(Default)]
+struct Scope {
+    /// Names in scope, innermost last.
+    names: Vec<(Ident, usize)>,
+    frame_size: usize,
+    captures: Vec<(Ident, Var)>,
+}
+
+pub struct Resolver {
+    scopes: Vec<Scope>,
+}
+
+impl Resolver {
+    pub fn resolve(expr: &Expr) -> Program {
+        let mut resolver = Self {
+            scopes: vec![Scope::default()],
+        };
+        let code = resolver.code(expr);
+        let scope = resolver.scopes.pop().unwrap();
+        Program {
+            frame_size: scope.frame_size,
+            code: Rc::new(code),
+        }
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn bind(&mut self, name: Ident) -> usize {
+        let scope = self.scope();
+        let slot = scope.frame_size;
+        scope.frame_size += 1;
+        scope.names.push((name, slot));
+        slot
+    }
+
+    fn lookup(&mut self, depth: usize, name: Ident) -> Option<Var> {
+        let scope = &self.scopes[depth];
+        if let Some((_, slot)) = scope.names.iter().rev().find(|(n, _)| *n == name) {
+            return Some(Var::Local(*slot));
+        }
+        if let Some(i) = scope.captures.iter().position(|(n, _)| *n == name) {
+            return Some(Var::Capture(i));
+        }
+        if depth == 0 {
+            return None;
+        }
+        let outer = self.lookup(depth - 1, name)?;
+        let captures = &mut self.scopes[depth].captures;
+        captures.push((name, outer));
+        Some(Var::Capture(captures.len() - 1))
+    }
+
+    fn code(&mut self, expr: &Expr) -> Code {
+        match expr {
+            Expr::Int(v) => Code::Int(*v),
+            Expr::Bool(v) => Code::Bool(*v),
+            Expr::Variable(name) => match self.lookup(self.scopes.len() - 1, *name) {
+                Some(var) => Code::Var(var),
+                None => Code::Unbound(*name),
+            },
+            Expr::Program(prog, ret) => {
+                let depth = self.scope().names.len();
+                let prog = prog.iter().map(|expr| Rc::new(self.code(expr))).collect();
+                let ret = self.code(ret);
+                self.scope().names.truncate(depth);
+                Code::Program(prog, Rc::new(ret))
+            }
+            Expr::Assign(name, _, expr) => {
+                if let Expr::Lambda(param, _, body) = expr.as_ref() {
+                    let slot = self.bind(*name);
+                    let mut fun = self.function(*param, body);
+                    fun.recursive = fun
+                        .captures
+                        .iter()
+                        .position(|(_, var)| *var == Var::Local(slot));
+                    Code::Let(slot, Rc::new(Code::Lambda(Rc::new(fun))))
+                } else {
+                    let code = self.code(expr);
+                    Code::Let(self.bind(*name), Rc::new(code))
+                }
+            }
+            Expr::BinOp(op, exp1, exp2) => {
+                Code::BinOp(*op, Rc::new(self.code(exp1)), Rc::new(self.code(exp2)))
+            }
+            Expr::Compare(first, rest) => Code::Compare(
+                Rc::new(self.code(first)),
+                rest.iter()
+                    .map(|(op, expr)| (*op, Rc::new(self.code(expr))))
+                    .collect(),
+            ),
+            Expr::UnaryOp(op, expr) => Code::UnaryOp(*op, Rc::new(self.code(expr))),
+            Expr::If(cond, exp1, exp2) => Code::If(
+                Rc::new(self.code(cond)),
+                Rc::new(self.code(exp1)),
+                Rc::new(self.code(exp2)),
+            ),
+            Expr::Lambda(param, _, body) => Code::Lambda(Rc::new(self.function(*param, body))),
+            Expr::App(fun, arg) => Code::App(Rc::new(self.code(fun)), Rc::new(self.code(arg))),
+            Expr::Compose(first, then) => {
+                Code::Compose(Rc::new(self.code(first)), Rc::new(self.code(then)))
+            }
+        }
+    }
+
+    fn function(&mut self, param: Ident, body: &Expr) -> Function {
+        self.scopes.push(Scope::default());
+        self.bind(param);
+        let body = self.code(body);
+        let scope = self.scopes.pop().unwrap();
+        Function {
+            param,
+            captures: scope.captures,
+            recursive: None,
+            frame_size: scope.frame_size,
+            body: Rc::new(body),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::desugar::parse_core;
+
+    use super::{Code, Resolver, Var};
+
+    #[test]
+    fn resolve_slots_and_captures() {
+        let expr = parse_core(
+            "let x = 1; let f = lambda (y) { let z = y; lambda (w) { x + z + f(w) } }; let x = 2; x",
+        )
+        .unwrap();
+        let program = Resolver::resolve(&expr);
+        assert_eq!(program.frame_size, 3);
+        let Code::Program(prog, ret) = program.code.as_ref() else {
+            panic!("expected a program")
+        };
+        assert_eq!(**ret, Code::Var(Var::Local(2)));
+        let Code::Let(1, f) = prog[1].as_ref() else {
+            panic!("expected f in slot 1")
+        };
+        let Code::Lambda(f) = f.as_ref() else {
+            panic!("expected a lambda")
+        };
+        assert_eq!(f.frame_size, 2);
+        assert_eq!(f.recursive, Some(1));
+        assert_eq!(
+            f.captures,
+            [("x".into(), Var::Local(0)), ("f".into(), Var::Local(1))]
+        );
+        let Code::Program(_, inner) = f.body.as_ref() else {
+            panic!("expected a program")
+        };
+        let Code::Lambda(inner) = inner.as_ref() else {
+            panic!("expected a lambda")
+        };
+        assert_eq!(
+            inner.captures,
+            [
+                ("x".into(), Var::Capture(0)),
+                ("z".into(), Var::Local(1)),
+                ("f".into(), Var::Capture(1)),
+            ]
+        );
+    }
+}
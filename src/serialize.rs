@@ -0,0 +1,604 @@
+//! The file format of compiled bytecode, so scripts can be shipped
+//! pre-compiled and run without parsing or type checking them again.
+//!
+//! All integers are little-endian, and a file is laid out as:
+//!
+//! - a header: the magic bytes `RSBC`, the format version as a `u16` and the
+//!   index of the top-level function as a `u32`;
+//! - the constant pool shared by all functions: a count, then each constant
+//!   as a tag byte, 0 for an int and 1 for a bool, and its value;
+//! - the function table: a count, then for each function its frame size, its
+//!   recursive upvalue or `u32::MAX`, where its upvalues come from, its
+//!   constants as indices into the pool, and its code;
+//! - debug info: for each function the names of its parameter and upvalues,
+//!   and its table of source spans;
+//! - an FNV-1a checksum of everything before it.
+//!
+//! Loading validates everything, down to the stack effect of the code of
+//! every function, so that the VM never runs off its code or its stack.
+
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Ok, Result};
+
+use crate::{
+    bytecode::{Module, Op, Proto},
+    ident::Ident,
+    internal_value::Value,
+    operator::{BinOp, UnOp},
+    resolve::Var,
+    span::Span,
+};
+
+const MAGIC: &[u8; 4] = b"RSBC";
+
+/// The format version; files of any other version are rejected.
+pub const VERSION: u16 = 1;
+
+/// Marks a function that does not refer to itself.
+const NOT_RECURSIVE: u32 = u32::MAX;
+
+/// Whether `bytes` look like compiled bytecode rather than source text.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl Module {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut pool: Vec<Value> = vec![];
+        let constants: Vec<Vec<u32>> = self
+            .protos
+            .iter()
+            .map(|proto| {
+                proto
+                    .constants
+                    .iter()
+                    .map(|val| match pool.iter().position(|c| c == val) {
+                        Some(index) => index as u32,
+                        None => {
+                            pool.push(val.clone());
+                            pool.len() as u32 - 1
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut w = Writer::default();
+        w.bytes.extend(MAGIC);
+        w.u16(VERSION);
+        w.u32(self.main as u32);
+
+        w.u32(pool.len() as u32);
+        for val in &pool {
+            match val {
+                Value::Int(v) => {
+                    w.u8(0);
+                    w.u64(*v as u64);
+                }
+                Value::Bool(b) => {
+                    w.u8(1);
+                    w.u8(*b as u8);
+                }
+                _ => unreachable!("constant {val} is not a literal"),
+            }
+        }
+
+        w.u32(self.protos.len() as u32);
+        for (proto, constants) in self.protos.iter().zip(&constants) {
+            w.u32(proto.frame_size as u32);
+            w.u32(proto.recursive.map_or(NOT_RECURSIVE, |rec| rec as u32));
+            w.u32(proto.captures.len() as u32);
+            for (_, var) in &proto.captures {
+                match var {
+                    Var::Local(slot) => {
+                        w.u8(0);
+                        w.u32(*slot as u32);
+                    }
+                    Var::Capture(index) => {
+                        w.u8(1);
+                        w.u32(*index as u32);
+                    }
+                }
+            }
+            w.u32(constants.len() as u32);
+            for index in constants {
+                w.u32(*index);
+            }
+            w.u32(proto.code.len() as u32);
+            for op in &proto.code {
+                w.op(*op);
+            }
+        }
+
+        for proto in &self.protos {
+            w.str(proto.param.as_str());
+            for (name, _) in &proto.captures {
+                w.str(name.as_str());
+            }
+            w.u32(proto.spans.len() as u32);
+            for (pc, span) in &proto.spans {
+                w.u32(*pc as u32);
+                w.u32(span.start as u32);
+                w.u32(span.end as u32);
+            }
+        }
+
+        let checksum = fnv1a(&w.bytes);
+        w.u32(checksum);
+        w.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module> {
+        if !bytes.starts_with(MAGIC) {
+            bail!("not an rscript bytecode file");
+        }
+        let mut r = Reader {
+            bytes,
+            pos: MAGIC.len(),
+        };
+        let version = r.u16()?;
+        if version != VERSION {
+            bail!("unsupported bytecode version {version}, expected {VERSION}");
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        if fnv1a(body) != u32::from_le_bytes(checksum.try_into()?) {
+            bail!("corrupt bytecode: checksum mismatch");
+        }
+        r.bytes = body;
+        let main = r.u32()? as usize;
+
+        let mut pool = vec![];
+        for _ in 0..r.u32()? {
+            pool.push(match r.u8()? {
+                0 => Value::Int(r.u64()? as i64),
+                1 => Value::Bool(match r.u8()? {
+                    0 => false,
+                    1 => true,
+                    b => bail!("corrupt bytecode: bool constant {b}"),
+                }),
+                tag => bail!("corrupt bytecode: constant tag {tag}"),
+            });
+        }
+
+        let mut protos = vec![];
+        for _ in 0..r.u32()? {
+            let frame_size = r.u32()? as usize;
+            let recursive = match r.u32()? {
+                NOT_RECURSIVE => None,
+                rec => Some(rec as usize),
+            };
+            let mut captures = vec![];
+            for _ in 0..r.u32()? {
+                let var = match r.u8()? {
+                    0 => Var::Local(r.u32()? as usize),
+                    1 => Var::Capture(r.u32()? as usize),
+                    tag => bail!("corrupt bytecode: upvalue tag {tag}"),
+                };
+                captures.push((Ident::intern(""), var));
+            }
+            let mut constants = vec![];
+            for _ in 0..r.u32()? {
+                let index = r.u32()? as usize;
+                let val = pool
+                    .get(index)
+                    .context("corrupt bytecode: constant out of range")?;
+                constants.push(val.clone());
+            }
+            let mut code = vec![];
+            for _ in 0..r.u32()? {
+                code.push(r.op()?);
+            }
+            protos.push(Proto {
+                param: Ident::intern(""),
+                captures,
+                recursive,
+                frame_size,
+                constants,
+                code,
+                spans: vec![],
+            });
+        }
+
+        for proto in &mut protos {
+            proto.param = Ident::intern(r.str()?);
+            for (name, _) in &mut proto.captures {
+                *name = Ident::intern(r.str()?);
+            }
+            for _ in 0..r.u32()? {
+                let pc = r.u32()? as usize;
+                let span = Span::new(r.u32()? as usize, r.u32()? as usize);
+                proto.spans.push((pc, span));
+            }
+        }
+        if r.pos != body.len() {
+            bail!("corrupt bytecode: trailing bytes");
+        }
+
+        let module = Module {
+            protos: protos.into_iter().map(Rc::new).collect(),
+            main,
+        };
+        module.validate()?;
+        Ok(module)
+    }
+
+    /// Checks that every index in the module is in range and that the code
+    /// of every function keeps the stack balanced.
+    fn validate(&self) -> Result<()> {
+        let main = self
+            .protos
+            .get(self.main)
+            .context("corrupt bytecode: no top-level function")?;
+        if !main.captures.is_empty() {
+            bail!("corrupt bytecode: top-level function with upvalues");
+        }
+        for (i, proto) in self.protos.iter().enumerate() {
+            if i != self.main && proto.frame_size == 0 {
+                bail!("corrupt bytecode: function {i} has no parameter slot");
+            }
+            // The VM allocates the whole frame on every call, so a frame may
+            // be no larger than the slots the function uses.
+            if proto.frame_size > self.slots_used(proto).max((i != self.main) as usize) {
+                bail!("corrupt bytecode: function {i} has unused frame slots");
+            }
+            if proto
+                .recursive
+                .is_some_and(|rec| rec >= proto.captures.len())
+            {
+                bail!("corrupt bytecode: function {i} refers to itself out of range");
+            }
+            for op in &proto.code {
+                self.validate_op(proto, *op)
+                    .with_context(|| format!("corrupt bytecode: in function {i}"))?;
+            }
+            validate_stack(&proto.code)
+                .with_context(|| format!("corrupt bytecode: in function {i}"))?;
+            if !proto.spans.windows(2).all(|w| w[0].0 < w[1].0)
+                || proto
+                    .spans
+                    .iter()
+                    .any(|(pc, span)| *pc >= proto.code.len() || span.start > span.end)
+            {
+                bail!("corrupt bytecode: bad spans in function {i}");
+            }
+        }
+        Ok(())
+    }
+
+    /// One more than the highest frame slot the code of `proto` reads,
+    /// writes or captures into a closure.
+    fn slots_used(&self, proto: &Proto) -> usize {
+        let captured = |index: u32| {
+            self.protos
+                .get(index as usize)
+                .into_iter()
+                .flat_map(|fun| &fun.captures)
+                .filter_map(|(_, var)| match var {
+                    Var::Local(slot) => Some(*slot),
+                    Var::Capture(_) => None,
+                })
+                .max()
+        };
+        proto
+            .code
+            .iter()
+            .filter_map(|op| match *op {
+                Op::Local(slot) | Op::SetLocal(slot) => Some(slot as usize),
+                Op::Closure(index) => captured(index),
+                _ => None,
+            })
+            .max()
+            .map_or(0, |slot| slot + 1)
+    }
+
+    fn validate_op(&self, proto: &Proto, op: Op) -> Result<()> {
+        let in_range = |index: u32, len: usize| (index as usize) < len;
+        let ok = match op {
+            Op::Const(index) => in_range(index, proto.constants.len()),
+            Op::Local(slot) | Op::SetLocal(slot) => in_range(slot, proto.frame_size),
+            Op::Capture(index) => in_range(index, proto.captures.len()),
+            Op::Closure(index) => {
+                let Some(fun) = self.protos.get(index as usize) else {
+                    bail!("closure of a missing function");
+                };
+                index as usize != self.main
+                    && fun.captures.iter().all(|(_, var)| match var {
+                        Var::Local(slot) => *slot < proto.frame_size,
+                        Var::Capture(i) => *i < proto.captures.len(),
+                    })
+            }
+            _ => true,
+        };
+        if !ok {
+            bail!("operand of {op:?} out of range");
+        }
+        Ok(())
+    }
+}
+
+/// Follows every path through `code`, checking that the stack never
+/// underflows, has the same height wherever paths meet, and that no path
+/// runs past the end.
+fn validate_stack(code: &[Op]) -> Result<()> {
+    let mut heights = vec![None; code.len()];
+    let mut paths = vec![(0, 0)];
+    while let Some((pc, height)) = paths.pop() {
+        let Some(op) = code.get(pc) else {
+            bail!("code runs past its end");
+        };
+        match heights[pc] {
+            Some(seen) if seen == height => continue,
+            Some(_) => bail!("inconsistent stack height at {pc}"),
+            None => heights[pc] = Some(height),
+        }
+        let (needs, next): (usize, &[(usize, isize)]) = match *op {
+            Op::Const(_) | Op::Local(_) | Op::Capture(_) | Op::Closure(_) => (0, &[(pc + 1, 1)]),
+            Op::SetLocal(_) | Op::UnOp(_) => (1, &[(pc + 1, 0)]),
+            Op::Swap => (2, &[(pc + 1, 0)]),
+            Op::Pop => (1, &[(pc + 1, -1)]),
+            Op::BinOp(_) | Op::Compose | Op::Call => (2, &[(pc + 1, -1)]),
+            Op::Compare(_, fail) => (2, &[(pc + 1, -1), (fail as usize, -2)]),
+            Op::Jump(target) => (0, &[(target as usize, 0)]),
+            Op::JumpIfFalse(target) => (1, &[(pc + 1, -1), (target as usize, -1)]),
+            Op::Unbound => (0, &[]),
+            Op::TailCall => (2, &[]),
+            Op::Return => (1, &[]),
+        };
+        if height < needs {
+            bail!("stack underflow at {pc}");
+        }
+        for (to, effect) in next {
+            paths.push((*to, height.checked_add_signed(*effect).unwrap()));
+        }
+    }
+    Ok(())
+}
+
+/// The 32-bit FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.bytes.extend(v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.bytes.extend(v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.bytes.extend(v.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes.extend(s.as_bytes());
+    }
+
+    fn op(&mut self, op: Op) {
+        let binop = |op: BinOp| BinOp::ALL.iter().position(|o| *o == op).unwrap() as u8;
+        match op {
+            Op::Const(index) => self.operand(0, index),
+            Op::Local(slot) => self.operand(1, slot),
+            Op::SetLocal(slot) => self.operand(2, slot),
+            Op::Capture(index) => self.operand(3, index),
+            Op::Unbound => self.u8(4),
+            Op::Pop => self.u8(5),
+            Op::Swap => self.u8(6),
+            Op::BinOp(op) => {
+                self.u8(7);
+                self.u8(binop(op));
+            }
+            Op::UnOp(op) => {
+                self.u8(8);
+                self.u8(UnOp::ALL.iter().position(|o| *o == op).unwrap() as u8);
+            }
+            Op::Compare(op, fail) => {
+                self.u8(9);
+                self.u8(binop(op));
+                self.u32(fail);
+            }
+            Op::Jump(target) => self.operand(10, target),
+            Op::JumpIfFalse(target) => self.operand(11, target),
+            Op::Closure(index) => self.operand(12, index),
+            Op::Compose => self.u8(13),
+            Op::Call => self.u8(14),
+            Op::TailCall => self.u8(15),
+            Op::Return => self.u8(16),
+        }
+    }
+
+    fn operand(&mut self, opcode: u8, operand: u32) {
+        self.u8(opcode);
+        self.u32(operand);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(len))
+            .context("corrupt bytecode: truncated file")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).context("corrupt bytecode: name is not UTF-8")
+    }
+
+    fn op(&mut self) -> Result<Op> {
+        Ok(match self.u8()? {
+            0 => Op::Const(self.u32()?),
+            1 => Op::Local(self.u32()?),
+            2 => Op::SetLocal(self.u32()?),
+            3 => Op::Capture(self.u32()?),
+            4 => Op::Unbound,
+            5 => Op::Pop,
+            6 => Op::Swap,
+            7 => Op::BinOp(self.binop()?),
+            8 => Op::UnOp(
+                *UnOp::ALL
+                    .get(self.u8()? as usize)
+                    .context("corrupt bytecode: unknown operator")?,
+            ),
+            9 => Op::Compare(self.binop()?, self.u32()?),
+            10 => Op::Jump(self.u32()?),
+            11 => Op::JumpIfFalse(self.u32()?),
+            12 => Op::Closure(self.u32()?),
+            13 => Op::Compose,
+            14 => Op::Call,
+            15 => Op::TailCall,
+            16 => Op::Return,
+            opcode => bail!("corrupt bytecode: unknown opcode {opcode}"),
+        })
+    }
+
+    fn binop(&mut self) -> Result<BinOp> {
+        BinOp::ALL
+            .get(self.u8()? as usize)
+            .copied()
+            .context("corrupt bytecode: unknown operator")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        bytecode::{Module, Op},
+        desugar::desugar,
+        eval::Eval,
+        internal_value::Value,
+        operator::BinOp,
+        parse::Parser,
+        resolve::Resolver,
+    };
+
+    use super::VERSION;
+
+    fn compile(src: &str) -> Module {
+        let (expr, spans) = desugar(&Parser::new(src).program().unwrap());
+        Module::compile_with_spans(&Resolver::resolve(&expr), &spans)
+    }
+
+    #[test]
+    fn bytecode_round_trip() {
+        let src = "let k = 2; let f = lambda (n: int) { if (n < 1 <= 1) { k } else { f(n - 1) } }; (f >> (* 3))(5)";
+        let module = compile(src);
+        let loaded = Module::from_bytes(&module.to_bytes()).unwrap();
+        assert_eq!(loaded, module);
+        assert_eq!(Eval::new().eval_module(&loaded).unwrap(), Value::Int(6));
+
+        let f = &loaded.protos[0];
+        let source = |op: Op| {
+            let pc = f.code.iter().position(|o| *o == op).unwrap();
+            let span = f.span_at(pc).unwrap();
+            &src[span.start..span.end]
+        };
+        assert_eq!(source(Op::BinOp(BinOp::Sub)), "n - 1");
+        assert_eq!(source(Op::TailCall), "f(n - 1)");
+        assert_eq!(source(Op::Capture(0)), "k");
+        assert_eq!(
+            source(Op::Return),
+            "if (n < 1 <= 1) { k } else { f(n - 1) }"
+        );
+    }
+
+    #[test]
+    fn bytecode_rejects_bad_files() {
+        let bytes = compile("let x = 1; x + 2").to_bytes();
+        let err = |bytes: &[u8]| Module::from_bytes(bytes).unwrap_err().to_string();
+
+        assert_eq!(err(b"let x = 1; x"), "not an rscript bytecode file");
+        let mut old = bytes.clone();
+        old[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            err(&old),
+            format!(
+                "unsupported bytecode version {}, expected {VERSION}",
+                VERSION + 1
+            )
+        );
+        let mut flipped = bytes.clone();
+        flipped[12] ^= 1;
+        assert_eq!(err(&flipped), "corrupt bytecode: checksum mismatch");
+        assert_eq!(
+            err(&bytes[..bytes.len() - 1]),
+            "corrupt bytecode: checksum mismatch"
+        );
+    }
+
+    #[test]
+    fn bytecode_rejects_oversized_frames() {
+        let mut module = compile("let x = 1; let f = lambda (n: int) { n + x }; f(2)");
+        let main = module.main;
+        Rc::get_mut(&mut module.protos[main]).unwrap().frame_size = u32::MAX as usize;
+        let err = Module::from_bytes(&module.to_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("corrupt bytecode: function {main} has unused frame slots")
+        );
+        let module = compile("let x = 1; let f = lambda (n: int) { n + x }; f(2)");
+        assert_eq!(Module::from_bytes(&module.to_bytes()).unwrap(), module);
+    }
+
+    #[test]
+    fn bytecode_rejects_invalid_code() {
+        for code in [
+            vec![Op::Pop, Op::Return],
+            vec![Op::Local(7), Op::Return],
+            vec![Op::Const(0)],
+            vec![Op::Const(0), Op::Const(0), Op::JumpIfFalse(0), Op::Return],
+            vec![Op::Const(0), Op::Jump(9)],
+            vec![Op::Closure(0), Op::Return],
+        ] {
+            let mut module = compile("1");
+            Rc::get_mut(&mut module.protos[0]).unwrap().code = code.clone();
+            let err = Module::from_bytes(&module.to_bytes()).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with("corrupt bytecode: in function 0"),
+                "{code:?}: {err}"
+            );
+        }
+    }
+}
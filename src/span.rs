@@ -0,0 +1,53 @@
+//! Positions in the source text.
+
+use std::fmt;
+
+/// A range of bytes in the source text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// The spans of the nodes of a core expression, by node id: the position
+/// of the node in a pre-order walk that visits children in field order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Spans(Vec<Span>);
+
+impl Spans {
+    pub fn push(&mut self, span: Span) {
+        self.0.push(span);
+    }
+
+    pub fn get(&self, id: usize) -> Option<Span> {
+        self.0.get(id).copied()
+    }
+
+    /// The id of the smallest node whose span contains `offset`; of nodes
+    /// with the same span, the outermost.
+    pub fn innermost(&self, offset: usize) -> Option<usize> {
+        (0..self.0.len())
+            .filter(|id| self.0[*id].start <= offset && offset < self.0[*id].end)
+            .min_by_key(|id| self.0[*id].end - self.0[*id].start)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
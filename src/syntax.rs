@@ -0,0 +1,56 @@
+//! The surface syntax: programs as the parser reads them, sugar and all,
+//! with the span of every node. `desugar` turns them into the core `Expr`.
+
+use crate::{
+    ident::Ident,
+    operator::{BinOp, UnOp},
+    span::Span,
+    types::Type,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Syntax {
+    pub kind: SyntaxKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxKind {
+    Int(i64),
+    Bool(bool),
+    Variable(Ident),
+    /// `let`s followed by the value of the block.
+    Block(Vec<Syntax>, Box<Syntax>),
+    Let(Ident, Option<Type>, Box<Syntax>),
+    Binary(Operator, Box<Syntax>, Box<Syntax>),
+    /// A comparison chain `a < b <= c`.
+    Compare(Box<Syntax>, Vec<(BinOp, Syntax)>),
+    Unary(UnOp, Box<Syntax>),
+    If(Box<Syntax>, Box<Syntax>, Box<Syntax>),
+    Lambda(Ident, Option<Type>, Box<Syntax>),
+    App(Box<Syntax>, Box<Syntax>),
+    /// An operator section, a function of its missing operands: `(+)`,
+    /// `(* 2)` or `(10 -)`.
+    Section(Operator, Option<Box<Syntax>>, Option<Box<Syntax>>),
+}
+
+/// What a binary operator means.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Builtin(BinOp),
+    /// `x |> f`, applied directly as `f(x)`.
+    Pipe,
+    /// `f >> g` and `g << f`, both meaning "apply `f`, then `g`".
+    Compose {
+        flipped: bool,
+    },
+    /// A user-declared operator, applied as a curried call to the function
+    /// its declaration bound to the name.
+    User(Ident),
+}
+
+impl Syntax {
+    pub fn new(kind: SyntaxKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
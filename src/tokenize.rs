@@ -1,10 +1,12 @@
+use crate::{ident::Ident, span::Span};
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Token {
     Int(i64),
     Symbol(String),
     Keyword(String),
     Type(String),
-    Ident(String),
+    Ident(Ident),
 }
 
 pub struct Tokenizer<'a> {
@@ -16,21 +18,24 @@ impl<'a> Tokenizer<'a> {
         Self { input }
     }
 
-    pub fn tokenize(&self) -> Vec<Token> {
+    /// The tokens of the input, each with its span.
+    pub fn tokenize(&self) -> Vec<(Token, Span)> {
         let parens: &str = "(){}[]";
-        let keywords: Vec<&str> = vec!["true", "false", "if", "else", "let", "lambda"];
+        let keywords: Vec<&str> = vec![
+            "true", "false", "if", "else", "let", "lambda", "infixl", "infixr", "infix",
+        ];
         let types: Vec<&str> = vec!["int", "bool"];
 
         let mut ret = vec![];
-        let mut program = self.input.chars().peekable();
-        while let Some(ch) = program.next() {
+        let mut program = self.input.char_indices().peekable();
+        while let Some((start, ch)) = program.next() {
             if ch.is_whitespace() {
                 continue;
             }
 
             if ch.is_ascii_digit() {
                 let mut numstr = ch.to_string();
-                while let Some(numch) = program.peek() {
+                while let Some((_, numch)) = program.peek() {
                     if numch.is_ascii_digit() {
                         numstr.push(*numch);
                         let _ = program.next();
@@ -39,7 +44,7 @@ impl<'a> Tokenizer<'a> {
                     }
                 }
                 let num = numstr.parse::<i64>().unwrap();
-                ret.push(Token::Int(num));
+                ret.push((Token::Int(num), Span::new(start, start + numstr.len())));
                 continue;
             }
 
@@ -47,25 +52,26 @@ impl<'a> Tokenizer<'a> {
                 let mut signs = ch.to_string();
 
                 if parens.contains(ch) {
-                    ret.push(Token::Symbol(signs));
+                    ret.push((Token::Symbol(signs), Span::new(start, start + 1)));
                     continue;
                 }
 
-                while let Some(punctch) = program.peek() {
-                    if punctch.is_ascii_punctuation() {
+                while let Some((_, punctch)) = program.peek() {
+                    if punctch.is_ascii_punctuation() && !parens.contains(*punctch) {
                         signs.push(*punctch);
                         let _ = program.next();
                     } else {
                         break;
                     }
                 }
-                ret.push(Token::Symbol(signs));
+                let span = Span::new(start, start + signs.len());
+                ret.push((Token::Symbol(signs), span));
                 continue;
             }
 
             if ch.is_ascii_alphabetic() {
                 let mut ident = ch.to_string();
-                while let Some(identch) = program.peek() {
+                while let Some((_, identch)) = program.peek() {
                     if identch.is_ascii_alphanumeric() {
                         ident.push(*identch);
                         let _ = program.next();
@@ -74,12 +80,13 @@ impl<'a> Tokenizer<'a> {
                     }
                 }
 
+                let span = Span::new(start, start + ident.len());
                 if keywords.contains(&ident.as_str()) {
-                    ret.push(Token::Keyword(ident))
+                    ret.push((Token::Keyword(ident), span))
                 } else if types.contains(&ident.as_str()) {
-                    ret.push(Token::Type(ident))
+                    ret.push((Token::Type(ident), span))
                 } else {
-                    ret.push(Token::Ident(ident));
+                    ret.push((Token::Ident(Ident::intern(&ident)), span));
                 }
                 continue;
             }
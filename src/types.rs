@@ -1,6 +1,7 @@
 use core::fmt;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Type {
     Int,
     Bool,
@@ -12,6 +13,63 @@ impl Type {
     pub fn func(t1: Type, t2: Type) -> Self {
         Type::Func(Box::new(t1), Box::new(t2))
     }
+
+    /// The type with every bound type variable replaced by its binding, so
+    /// that only unconstrained variables are left.
+    pub fn resolve(&self) -> Type {
+        match self {
+            Type::Func(t1, t2) => Type::func(t1.resolve(), t2.resolve()),
+            Type::TypeVar(_, t) => match &*t.borrow() {
+                Some(t) => t.resolve(),
+                None => self.clone(),
+            },
+            t => t.clone(),
+        }
+    }
+
+    /// Adds the ids of the unconstrained type variables in the type to
+    /// `vars`.
+    fn free_vars(&self, vars: &mut Vec<u64>) {
+        match self {
+            Type::Func(t1, t2) => {
+                t1.free_vars(vars);
+                t2.free_vars(vars);
+            }
+            Type::TypeVar(id, t) => match &*t.borrow() {
+                Some(t) => t.free_vars(vars),
+                None if !vars.contains(id) => vars.push(*id),
+                None => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// The type with the unconstrained type variables in `vars` replaced.
+    fn substitute(&self, vars: &HashMap<u64, Type>) -> Type {
+        match self {
+            Type::Func(t1, t2) => Type::func(t1.substitute(vars), t2.substitute(vars)),
+            Type::TypeVar(id, t) => match &*t.borrow() {
+                Some(t) => t.substitute(vars),
+                None => vars.get(id).cloned().unwrap_or_else(|| self.clone()),
+            },
+            t => t.clone(),
+        }
+    }
+}
+
+/// The type of a `let`-bound name, which is polymorphic in `vars`: every
+/// use of the name gets its own copy of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scheme {
+    vars: Vec<u64>,
+    ty: Type,
+}
+
+impl Scheme {
+    /// A type that is the same at every use.
+    fn mono(ty: Type) -> Self {
+        Self { vars: vec![], ty }
+    }
 }
 
 impl fmt::Display for Type {
@@ -20,20 +78,42 @@ impl fmt::Display for Type {
             Type::Bool => write!(f, "bool"),
             Type::Int => write!(f, "int"),
             Type::Func(t1, t2) => write!(f, "({t1} -> {t2})"),
-            Type::TypeVar(id, _) => write!(f, "t{id}"),
+            Type::TypeVar(id, t) => match &*t.borrow() {
+                Some(t) => write!(f, "{t}"),
+                None => write!(f, "t{id}"),
+            },
         }
     }
 }
 
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, error, rc::Rc};
 
 use anyhow::{bail, Ok, Result};
 
-use crate::expression::Expr;
+use crate::{
+    expression::Expr,
+    ident::Ident,
+    span::{Span, Spans},
+};
+
+/// A type error, with the span of the innermost node it was found at.
+#[derive(Debug)]
+pub struct TypeError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)
+    }
+}
+
+impl error::Error for TypeError {}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TypeEnv {
-    env: HashMap<String, Type>,
+    env: HashMap<Ident, Scheme>,
     outer: Option<Rc<RefCell<TypeEnv>>>,
 }
 
@@ -52,7 +132,7 @@ impl TypeEnv {
         }
     }
 
-    fn get(&self, name: String) -> Result<Type> {
+    fn get(&self, name: Ident) -> Result<Scheme> {
         if let Some(val) = self.env.get(&name) {
             Ok(val.clone())
         } else if let Some(outer) = &self.outer {
@@ -62,28 +142,92 @@ impl TypeEnv {
         }
     }
 
-    fn set(&mut self, name: String, val: Type) {
+    fn set(&mut self, name: Ident, val: Scheme) {
         self.env.insert(name, val);
     }
+
+    /// Adds the ids of the type variables free in the environment to `vars`.
+    fn free_vars(&self, vars: &mut Vec<u64>) {
+        for scheme in self.env.values() {
+            let mut free = vec![];
+            scheme.ty.free_vars(&mut free);
+            vars.extend(free.into_iter().filter(|id| !scheme.vars.contains(id)));
+        }
+        if let Some(outer) = &self.outer {
+            outer.borrow().free_vars(vars);
+        }
+    }
+}
+
+/// The types inferred for the nodes of an expression, by node id: the
+/// position of the node in a pre-order walk, as in `Spans` and `Visitor`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TypeTable {
+    nodes: Vec<Option<Type>>,
+    bindings: HashMap<usize, Type>,
+}
+
+impl TypeTable {
+    /// The type of the value of a node.
+    pub fn node(&self, id: usize) -> Option<&Type> {
+        self.nodes.get(id)?.as_ref()
+    }
+
+    /// The type of the name bound by a `let` or lambda node: the value of a
+    /// `let`, or the parameter of a lambda.
+    pub fn binding(&self, id: usize) -> Option<&Type> {
+        self.bindings.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
 }
 
 pub struct TypeInfer {
     env: Rc<RefCell<TypeEnv>>,
     next_typevar_id: u64,
+    /// The spans of the nodes, from `desugar`, to locate errors with.
+    spans: Spans,
+    /// The types found so far, by node id; unresolved until `types`.
+    table: TypeTable,
 }
 
 impl TypeInfer {
     pub fn new() -> Self {
+        Self::with_spans(Spans::default())
+    }
+
+    /// A type checker whose errors point at the spans of the nodes.
+    pub fn with_spans(spans: Spans) -> Self {
         Self {
             env: Rc::new(RefCell::new(TypeEnv::new())),
             next_typevar_id: 0,
+            spans,
+            table: TypeTable::default(),
         }
     }
 
-    fn from(env: TypeEnv, next: u64) -> Self {
-        Self {
-            env: Rc::new(RefCell::new(env)),
-            next_typevar_id: next,
+    /// The fully resolved types of every node and binding inferred so far.
+    pub fn types(&self) -> TypeTable {
+        let resolve = |ty: &Type| ty.resolve();
+        TypeTable {
+            nodes: self
+                .table
+                .nodes
+                .iter()
+                .map(|ty| ty.as_ref().map(resolve))
+                .collect(),
+            bindings: self
+                .table
+                .bindings
+                .iter()
+                .map(|(id, ty)| (*id, resolve(ty)))
+                .collect(),
         }
     }
 
@@ -93,100 +237,161 @@ impl TypeInfer {
         ret
     }
 
+    /// A copy of the type of `scheme` with fresh type variables for the ones
+    /// it is polymorphic in.
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        if scheme.vars.is_empty() {
+            return scheme.ty.clone();
+        }
+        let vars = scheme
+            .vars
+            .iter()
+            .map(|id| (*id, self.new_typevar()))
+            .collect();
+        scheme.ty.substitute(&vars)
+    }
+
+    /// The type of a `let`, polymorphic in the type variables that nothing
+    /// in the environment constrains.
+    fn generalize(&self, ty: &Type) -> Scheme {
+        let mut env = vec![];
+        self.env.borrow().free_vars(&mut env);
+        let mut vars = vec![];
+        ty.free_vars(&mut vars);
+        vars.retain(|id| !env.contains(id));
+        Scheme {
+            vars,
+            ty: ty.clone(),
+        }
+    }
+
     pub fn infer_type(&mut self, ast: &Expr) -> Result<Type> {
+        let node = self.table.nodes.len();
+        self.table.nodes.push(None);
+        let ty = self
+            .infer_node(node, ast)
+            .map_err(|err| match self.spans.get(node) {
+                Some(span) if !err.is::<TypeError>() => TypeError {
+                    span,
+                    message: err.to_string(),
+                }
+                .into(),
+                _ => err,
+            })?;
+        self.table.nodes[node] = Some(ty.clone());
+        Ok(ty)
+    }
+
+    fn infer_node(&mut self, node: usize, ast: &Expr) -> Result<Type> {
         match &ast {
             Expr::Int(_) => Ok(Type::Int),
             Expr::Bool(_) => Ok(Type::Bool),
             Expr::Variable(name) => {
-                let actual_type = self.env.borrow().get(name.clone())?;
-                Ok(actual_type)
+                let scheme = self.env.borrow().get(*name)?;
+                Ok(self.instantiate(&scheme))
             }
             Expr::Program(v, ret) => {
                 for expr in v {
                     let _ = self.infer_type(expr)?;
                 }
-                let ret_type = self.infer_type(&ret)?;
+                let ret_type = self.infer_type(ret)?;
                 Ok(ret_type)
             }
-            Expr::BinOp(op, exp1, exp2) => match op.as_str() {
-                "+" | "-" | "*" | "/" => {
-                    let t1 = self.infer_type(&exp1)?;
-                    let t2 = self.infer_type(&exp2)?;
-                    Self::unify(&t1, &t2)?;
-                    Ok(Type::Int)
-                }
-                "==" | "!=" | "<" | ">" | "<=" | ">=" => {
-                    let t1 = self.infer_type(&exp1)?;
-                    let t2 = self.infer_type(&exp2)?;
-                    Self::unify(&t1, &t2)?;
-                    Ok(Type::Int)
-                }
-                "&&" | "||" => {
-                    let t1 = self.infer_type(&exp1)?;
-                    let t2 = self.infer_type(&exp2)?;
-                    Self::unify(&t1, &t2)?;
-                    Ok(Type::Bool)
-                }
-                _ => bail!("invalid operator: {}", op),
-            },
-            Expr::UnaryOp(op, expr) => match op.as_str() {
-                "-" => {
-                    let t1 = self.infer_type(&expr)?;
-                    Self::unify(&t1, &Type::Int)?;
-                    Ok(Type::Int)
-                }
-                "!" => {
-                    let t1 = self.infer_type(&expr)?;
-                    Self::unify(&t1, &Type::Bool)?;
-                    Ok(Type::Bool)
+            Expr::BinOp(op, exp1, exp2) => {
+                let info = op.info();
+                let t1 = self.infer_type(exp1)?;
+                let t2 = self.infer_type(exp2)?;
+                Self::unify(&t1, &info.operand)?;
+                Self::unify(&t2, &info.operand)?;
+                Ok(info.result)
+            }
+            Expr::Compare(first, rest) => {
+                let mut t1 = self.infer_type(first)?;
+                for (op, expr) in rest {
+                    let info = op.info();
+                    let t2 = self.infer_type(expr)?;
+                    Self::unify(&t1, &info.operand)?;
+                    Self::unify(&t2, &info.operand)?;
+                    t1 = t2;
                 }
-                _ => bail!("invalid operator: {}", op),
-            },
+                Ok(Type::Bool)
+            }
+            Expr::UnaryOp(op, expr) => {
+                let t1 = self.infer_type(expr)?;
+                Self::unify(&t1, &op.operand_type())?;
+                Ok(op.operand_type())
+            }
             Expr::If(cond, exp1, exp2) => {
-                let t0 = self.infer_type(&cond)?;
-                let t1 = self.infer_type(&exp1)?;
-                let t2 = self.infer_type(&exp2)?;
+                let t0 = self.infer_type(cond)?;
+                let t1 = self.infer_type(exp1)?;
+                let t2 = self.infer_type(exp2)?;
                 Self::unify(&t0, &Type::Bool)?;
                 Self::unify(&t1, &t2)?;
                 Ok(t1)
             }
             Expr::Assign(ident, ty, expr) => {
-                let nty = self.new_typevar();
-                self.env.borrow_mut().set(ident.clone(), nty);
-                let actual = self.infer_type(&expr)?;
+                // Only functions may refer to themselves.
+                let actual = if let Expr::Lambda(..) = expr.as_ref() {
+                    let nty = self.new_typevar();
+                    let shadowed = self
+                        .env
+                        .borrow_mut()
+                        .env
+                        .insert(*ident, Scheme::mono(nty.clone()));
+                    let actual = self.infer_type(expr);
+                    // The binding for recursion must not keep the name from
+                    // being generalised, nor hide the one it shadows.
+                    match shadowed {
+                        Some(scheme) => self.env.borrow_mut().set(*ident, scheme),
+                        None => {
+                            self.env.borrow_mut().env.remove(ident);
+                        }
+                    }
+                    let actual = actual?;
+                    Self::unify(&nty, &actual)?;
+                    actual
+                } else {
+                    self.infer_type(expr)?
+                };
 
                 if let Some(expected) = ty {
                     Self::unify(expected, &actual)?;
                 }
-                self.env.borrow_mut().set(ident.clone(), actual.clone());
+                self.table.bindings.insert(node, actual.clone());
+                let scheme = self.generalize(&actual);
+                self.env.borrow_mut().set(*ident, scheme);
                 Ok(actual)
             }
             Expr::Lambda(var, ty, expr) => {
                 let nty = self.new_typevar();
-                let mut new_type_infer = Self::from(
-                    TypeEnv::with_outer(Rc::clone(&self.env)),
-                    self.next_typevar_id,
-                );
-                new_type_infer
-                    .env
-                    .borrow_mut()
-                    .set(var.clone(), nty.clone());
-                let ret_type = new_type_infer.infer_type(&expr)?;
-                self.next_typevar_id = new_type_infer.next_typevar_id;
+                let outer = Rc::clone(&self.env);
+                let mut env = TypeEnv::with_outer(Rc::clone(&outer));
+                env.set(*var, Scheme::mono(nty.clone()));
+                self.env = Rc::new(RefCell::new(env));
+                let ret_type = self.infer_type(expr);
+                self.env = outer;
+                let ret_type = ret_type?;
+                self.table.bindings.insert(node, nty.clone());
                 if ty.is_some() {
-                    Self::unify(&ty.as_ref().unwrap(), &nty)?;
+                    Self::unify(ty.as_ref().unwrap(), &nty)?;
                 }
                 Ok(Type::func(nty.clone(), ret_type))
             }
             Expr::App(fun, var) => {
-                let fun_type = self.infer_type(&fun)?;
-                let var_type = self.infer_type(&var)?;
-                if let Type::Func(dom, cod) = fun_type {
-                    Self::unify(&dom, &var_type)?;
-                    Ok(*cod)
-                } else {
-                    bail!("invalid func type")
-                }
+                let fun_type = self.infer_type(fun)?;
+                let var_type = self.infer_type(var)?;
+                let ret_type = self.new_typevar();
+                Self::unify(&fun_type, &Type::func(var_type, ret_type.clone()))?;
+                Ok(ret_type)
+            }
+            Expr::Compose(first, then) => {
+                // (a -> b) -> (b -> c) -> (a -> c)
+                let (a, b, c) = (self.new_typevar(), self.new_typevar(), self.new_typevar());
+                let first_type = self.infer_type(first)?;
+                Self::unify(&first_type, &Type::func(a.clone(), b.clone()))?;
+                let then_type = self.infer_type(then)?;
+                Self::unify(&then_type, &Type::func(b, c.clone()))?;
+                Ok(Type::func(a, c))
             }
         }
     }
@@ -196,8 +401,8 @@ impl TypeInfer {
             (Type::Bool, Type::Bool) => Ok(()),
             (Type::Int, Type::Int) => Ok(()),
             (Type::Func(arg1, ret1), Type::Func(arg2, ret2)) => {
-                Self::unify(&arg1, &arg2)?;
-                Self::unify(&ret1, &ret2)
+                Self::unify(arg1, arg2)?;
+                Self::unify(ret1, ret2)
             }
             (Type::TypeVar(id1, _), Type::TypeVar(id2, _)) if id1 == id2 => Ok(()),
             (Type::TypeVar(id1, t1), t2) => Self::unify_var(id1, t1, t2),
@@ -207,17 +412,21 @@ impl TypeInfer {
     }
 
     fn unify_var(id1: &u64, tref1: &Rc<RefCell<Option<Type>>>, ty2: &Type) -> Result<()> {
+        let bound = tref1.borrow().clone();
+        if let Some(ty1) = bound {
+            return Self::unify(&ty1, ty2);
+        }
         match ty2 {
-            Type::TypeVar(_, tref2) => {
-                if let Some(ref ty1) = *(*tref1).borrow() {
-                    Self::unify(ty1, ty2)
+            Type::TypeVar(id2, tref2) => {
+                let bound = tref2.borrow().clone();
+                if let Some(ty2i) = bound {
+                    Self::unify_var(id1, tref1, &ty2i)
+                } else if id1 == id2 {
+                    // Already the same variable; binding it would make a cycle.
+                    Ok(())
                 } else {
-                    if let Some(ref ty2i) = *(*tref2).borrow() {
-                        Self::unify_var(id1, tref1, ty2i)
-                    } else {
-                        *(*tref1).borrow_mut() = Some(ty2.clone());
-                        Ok(())
-                    }
+                    *(*tref1).borrow_mut() = Some(ty2.clone());
+                    Ok(())
                 }
             }
             _ => {
@@ -242,10 +451,173 @@ impl TypeInfer {
                 }
 
                 match *(*t1).borrow() {
-                    Some(ref t1) => Self::occur(n, &t1),
+                    Some(ref t1) => Self::occur(n, t1),
                     None => false,
                 }
             }
         }
     }
 }
+
+impl Default for TypeInfer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{desugar::desugar, desugar::parse_core, parse::Parser};
+
+    use super::{Type, TypeInfer};
+
+    fn infer(src: &str) -> String {
+        let expr = parse_core(src).unwrap();
+        rename(&TypeInfer::new().infer_type(&expr).unwrap().to_string())
+    }
+
+    /// Numbers the type variables of a printed type from `t0` on, in order of
+    /// appearance, so that types equal up to renaming print the same.
+    fn rename(ty: &str) -> String {
+        let mut names: Vec<&str> = vec![];
+        let mut out = String::new();
+        for piece in ty.split_inclusive(|c: char| !c.is_alphanumeric()) {
+            let word = piece.trim_end_matches(|c: char| !c.is_alphanumeric());
+            let is_var =
+                word.len() > 1 && word.starts_with('t') && word[1..].parse::<u64>().is_ok();
+            if !is_var {
+                out.push_str(piece);
+                continue;
+            }
+            let index = names
+                .iter()
+                .position(|name| *name == word)
+                .unwrap_or_else(|| {
+                    names.push(word);
+                    names.len() - 1
+                });
+            out.push_str(&format!("t{index}{}", &piece[word.len()..]));
+        }
+        out
+    }
+
+    #[test]
+    fn infer_pipe_and_compose() {
+        let prelude = "let inc = lambda (x) { x + 1 }; let pos = lambda (x) { x > 0 };";
+        assert_eq!(infer(&format!("{prelude} inc >> pos")), "(int -> bool)");
+        assert_eq!(infer(&format!("{prelude} 1 |> inc >> pos")), "bool");
+        assert_eq!(
+            infer("let twice = lambda (f) { f >> f }; twice"),
+            "((t0 -> t0) -> (t0 -> t0))"
+        );
+        assert!(parse_core("let pos = lambda (x) { x > 0 }; pos >> pos")
+            .map(|expr| TypeInfer::new().infer_type(&expr).is_err())
+            .unwrap());
+    }
+
+    #[test]
+    fn infer_let_polymorphism() {
+        assert_eq!(
+            infer(
+                "let twice = lambda (f) { f >> f }; let inc = lambda (x) { x + 1 }; \
+                 let not = lambda (b) { !b }; if (twice(not)(true)) { twice(inc)(1) } else { 0 }"
+            ),
+            "int"
+        );
+        assert_eq!(
+            infer(
+                "let first = lambda (n: int) { lambda (x) { if (n == 0) { x } else { first(n - 1)(x) } } }; \
+                 if (first(1)(true)) { first(2)(3) } else { 0 }"
+            ),
+            "int"
+        );
+        // A name bound by a lambda is the same type at every use, and so is a
+        // `let` of it.
+        for src in [
+            "lambda (x) { if (x) { 1 } else { x } }",
+            "lambda (x) { let y = x; if (y) { 1 } else { y } }",
+            "let f = lambda (x) { let x = x; if (x) { 1 } else { x } }; f(5)",
+        ] {
+            let expr = parse_core(src).unwrap();
+            assert!(TypeInfer::new().infer_type(&expr).is_err(), "{src}");
+        }
+    }
+
+    #[test]
+    fn infer_sections() {
+        assert_eq!(infer("(+)"), "(int -> (int -> int))");
+        assert_eq!(infer("(* 2)"), "(int -> int)");
+        assert_eq!(infer("(10 -)"), "(int -> int)");
+        assert_eq!(infer("(<)"), "(int -> (int -> bool))");
+        assert_eq!(infer("(&& true)"), "(bool -> bool)");
+        assert_eq!(infer("(3 |>)"), "((int -> t0) -> t0)");
+    }
+
+    #[test]
+    fn type_errors_point_at_the_source() {
+        let error = |src: &str| {
+            let (expr, spans) = desugar(&Parser::new(src).program().unwrap());
+            let err = TypeInfer::with_spans(spans).infer_type(&expr).unwrap_err();
+            err.to_string()
+        };
+        assert_eq!(
+            error("let x = 1; let y = x + true; y"),
+            "unify error: connot unify bool and int at 19..27"
+        );
+        assert_eq!(
+            error("let f = lambda (n) { n + 1 }; let g = lambda (b) { if (b) { f(1) } else { b } }; g"),
+            "unify error: connot unify bool and int at 51..77"
+        );
+        assert_eq!(
+            error("1 + (* 2)(3) * y"),
+            "type: undefined variable y at 15..16"
+        );
+    }
+
+    #[test]
+    fn infer_types_of_every_node_and_binding() {
+        let src = "let f = lambda (x) { x + 1 }; f(2) > 0";
+        let (expr, spans) = desugar(&Parser::new(src).program().unwrap());
+        let mut infer = TypeInfer::with_spans(spans.clone());
+        infer.infer_type(&expr).unwrap();
+        let types = infer.types();
+        let nodes: Vec<_> = (0..types.len())
+            .map(|id| types.node(id).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            nodes,
+            [
+                "bool",
+                "(int -> int)",
+                "(int -> int)",
+                "int",
+                "int",
+                "int",
+                "int",
+                "bool",
+                "int",
+                "(int -> int)",
+                "int",
+                "int",
+            ]
+        );
+        assert_eq!(types.binding(1).unwrap().to_string(), "(int -> int)");
+        assert_eq!(types.binding(2).unwrap().to_string(), "int");
+        assert!(types.binding(0).is_none());
+
+        // Hovering over the `f` of `f(2)`.
+        assert_eq!(spans.innermost(30), Some(9));
+
+        let mut infer = TypeInfer::new();
+        let expr = parse_core("let id = lambda (x) { x }; id").unwrap();
+        infer.infer_type(&expr).unwrap();
+        // The parameter of `id` is left unconstrained, and `id` returns it.
+        let types = infer.types();
+        let param = types.binding(2).unwrap();
+        assert!(matches!(param, Type::TypeVar(..)), "{param}");
+        assert_eq!(
+            types.node(2),
+            Some(&Type::func(param.clone(), param.clone()))
+        );
+    }
+}
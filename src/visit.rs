@@ -0,0 +1,310 @@
+//! Traversals of `Expr`: `Visitor` and `VisitorMut` walk an expression by
+//! reference, and `Fold` rebuilds it from its parts.
+//!
+//! Each trait has a method per variant whose default traverses the children
+//! in field order, so a pass overrides only the variants it cares about and
+//! calls back into the traversal for the rest. The free functions dispatch
+//! on the variant, and are what the `*_expr` methods default to.
+
+use crate::{
+    expression::Expr,
+    ident::Ident,
+    operator::{BinOp, UnOp},
+    types::Type,
+};
+
+pub trait Visitor {
+    fn visit_expr(&mut self, expr: &Expr) {
+        visit_expr(self, expr)
+    }
+
+    fn visit_int(&mut self, _v: i64) {}
+
+    fn visit_bool(&mut self, _v: bool) {}
+
+    fn visit_variable(&mut self, _name: Ident) {}
+
+    fn visit_program(&mut self, prog: &[Expr], ret: &Expr) {
+        for expr in prog {
+            self.visit_expr(expr);
+        }
+        self.visit_expr(ret);
+    }
+
+    fn visit_binop(&mut self, _op: BinOp, exp1: &Expr, exp2: &Expr) {
+        self.visit_expr(exp1);
+        self.visit_expr(exp2);
+    }
+
+    fn visit_compare(&mut self, first: &Expr, rest: &[(BinOp, Expr)]) {
+        self.visit_expr(first);
+        for (_, expr) in rest {
+            self.visit_expr(expr);
+        }
+    }
+
+    fn visit_unaryop(&mut self, _op: UnOp, expr: &Expr) {
+        self.visit_expr(expr);
+    }
+
+    fn visit_if(&mut self, cond: &Expr, exp1: &Expr, exp2: &Expr) {
+        self.visit_expr(cond);
+        self.visit_expr(exp1);
+        self.visit_expr(exp2);
+    }
+
+    fn visit_assign(&mut self, _name: Ident, _ty: Option<&Type>, expr: &Expr) {
+        self.visit_expr(expr);
+    }
+
+    fn visit_lambda(&mut self, _param: Ident, _ty: Option<&Type>, body: &Expr) {
+        self.visit_expr(body);
+    }
+
+    fn visit_app(&mut self, fun: &Expr, arg: &Expr) {
+        self.visit_expr(fun);
+        self.visit_expr(arg);
+    }
+
+    fn visit_compose(&mut self, first: &Expr, then: &Expr) {
+        self.visit_expr(first);
+        self.visit_expr(then);
+    }
+}
+
+pub fn visit_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::Int(v) => visitor.visit_int(*v),
+        Expr::Bool(v) => visitor.visit_bool(*v),
+        Expr::Variable(name) => visitor.visit_variable(*name),
+        Expr::Program(prog, ret) => visitor.visit_program(prog, ret),
+        Expr::BinOp(op, exp1, exp2) => visitor.visit_binop(*op, exp1, exp2),
+        Expr::Compare(first, rest) => visitor.visit_compare(first, rest),
+        Expr::UnaryOp(op, expr) => visitor.visit_unaryop(*op, expr),
+        Expr::If(cond, exp1, exp2) => visitor.visit_if(cond, exp1, exp2),
+        Expr::Assign(name, ty, expr) => visitor.visit_assign(*name, ty.as_ref(), expr),
+        Expr::Lambda(param, ty, body) => visitor.visit_lambda(*param, ty.as_ref(), body),
+        Expr::App(fun, arg) => visitor.visit_app(fun, arg),
+        Expr::Compose(first, then) => visitor.visit_compose(first, then),
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        visit_expr_mut(self, expr)
+    }
+
+    fn visit_int_mut(&mut self, _v: &mut i64) {}
+
+    fn visit_bool_mut(&mut self, _v: &mut bool) {}
+
+    fn visit_variable_mut(&mut self, _name: &mut Ident) {}
+
+    fn visit_program_mut(&mut self, prog: &mut Vec<Expr>, ret: &mut Expr) {
+        for expr in prog {
+            self.visit_expr_mut(expr);
+        }
+        self.visit_expr_mut(ret);
+    }
+
+    fn visit_binop_mut(&mut self, _op: &mut BinOp, exp1: &mut Expr, exp2: &mut Expr) {
+        self.visit_expr_mut(exp1);
+        self.visit_expr_mut(exp2);
+    }
+
+    fn visit_compare_mut(&mut self, first: &mut Expr, rest: &mut Vec<(BinOp, Expr)>) {
+        self.visit_expr_mut(first);
+        for (_, expr) in rest {
+            self.visit_expr_mut(expr);
+        }
+    }
+
+    fn visit_unaryop_mut(&mut self, _op: &mut UnOp, expr: &mut Expr) {
+        self.visit_expr_mut(expr);
+    }
+
+    fn visit_if_mut(&mut self, cond: &mut Expr, exp1: &mut Expr, exp2: &mut Expr) {
+        self.visit_expr_mut(cond);
+        self.visit_expr_mut(exp1);
+        self.visit_expr_mut(exp2);
+    }
+
+    fn visit_assign_mut(&mut self, _name: &mut Ident, _ty: &mut Option<Type>, expr: &mut Expr) {
+        self.visit_expr_mut(expr);
+    }
+
+    fn visit_lambda_mut(&mut self, _param: &mut Ident, _ty: &mut Option<Type>, body: &mut Expr) {
+        self.visit_expr_mut(body);
+    }
+
+    fn visit_app_mut(&mut self, fun: &mut Expr, arg: &mut Expr) {
+        self.visit_expr_mut(fun);
+        self.visit_expr_mut(arg);
+    }
+
+    fn visit_compose_mut(&mut self, first: &mut Expr, then: &mut Expr) {
+        self.visit_expr_mut(first);
+        self.visit_expr_mut(then);
+    }
+}
+
+pub fn visit_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match expr {
+        Expr::Int(v) => visitor.visit_int_mut(v),
+        Expr::Bool(v) => visitor.visit_bool_mut(v),
+        Expr::Variable(name) => visitor.visit_variable_mut(name),
+        Expr::Program(prog, ret) => visitor.visit_program_mut(prog, ret),
+        Expr::BinOp(op, exp1, exp2) => visitor.visit_binop_mut(op, exp1, exp2),
+        Expr::Compare(first, rest) => visitor.visit_compare_mut(first, rest),
+        Expr::UnaryOp(op, expr) => visitor.visit_unaryop_mut(op, expr),
+        Expr::If(cond, exp1, exp2) => visitor.visit_if_mut(cond, exp1, exp2),
+        Expr::Assign(name, ty, expr) => visitor.visit_assign_mut(name, ty, expr),
+        Expr::Lambda(param, ty, body) => visitor.visit_lambda_mut(param, ty, body),
+        Expr::App(fun, arg) => visitor.visit_app_mut(fun, arg),
+        Expr::Compose(first, then) => visitor.visit_compose_mut(first, then),
+    }
+}
+
+/// A rewrite of an expression. The defaults fold the children and rebuild
+/// the same node around them.
+pub trait Fold {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_expr(self, expr)
+    }
+
+    fn fold_int(&mut self, v: i64) -> Expr {
+        Expr::int(v)
+    }
+
+    fn fold_bool(&mut self, v: bool) -> Expr {
+        Expr::boolean(v)
+    }
+
+    fn fold_variable(&mut self, name: Ident) -> Expr {
+        Expr::variable(name)
+    }
+
+    fn fold_program(&mut self, prog: Vec<Expr>, ret: Expr) -> Expr {
+        let prog = prog.into_iter().map(|expr| self.fold_expr(expr)).collect();
+        Expr::program(prog, self.fold_expr(ret))
+    }
+
+    fn fold_binop(&mut self, op: BinOp, exp1: Expr, exp2: Expr) -> Expr {
+        let exp1 = self.fold_expr(exp1);
+        Expr::binop(op, exp1, self.fold_expr(exp2))
+    }
+
+    fn fold_compare(&mut self, first: Expr, rest: Vec<(BinOp, Expr)>) -> Expr {
+        let first = self.fold_expr(first);
+        let rest = rest
+            .into_iter()
+            .map(|(op, expr)| (op, self.fold_expr(expr)))
+            .collect();
+        Expr::compare(first, rest)
+    }
+
+    fn fold_unaryop(&mut self, op: UnOp, expr: Expr) -> Expr {
+        Expr::unaryop(op, self.fold_expr(expr))
+    }
+
+    fn fold_if(&mut self, cond: Expr, exp1: Expr, exp2: Expr) -> Expr {
+        let cond = self.fold_expr(cond);
+        let exp1 = self.fold_expr(exp1);
+        Expr::if_expr(cond, exp1, self.fold_expr(exp2))
+    }
+
+    fn fold_assign(&mut self, name: Ident, ty: Option<Type>, expr: Expr) -> Expr {
+        Expr::assign(name, ty, self.fold_expr(expr))
+    }
+
+    fn fold_lambda(&mut self, param: Ident, ty: Option<Type>, body: Expr) -> Expr {
+        Expr::lambda(param, ty, self.fold_expr(body))
+    }
+
+    fn fold_app(&mut self, fun: Expr, arg: Expr) -> Expr {
+        let fun = self.fold_expr(fun);
+        Expr::app(fun, self.fold_expr(arg))
+    }
+
+    fn fold_compose(&mut self, first: Expr, then: Expr) -> Expr {
+        let first = self.fold_expr(first);
+        Expr::compose(first, self.fold_expr(then))
+    }
+}
+
+pub fn fold_expr<F: Fold + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    match expr {
+        Expr::Int(v) => folder.fold_int(v),
+        Expr::Bool(v) => folder.fold_bool(v),
+        Expr::Variable(name) => folder.fold_variable(name),
+        Expr::Program(prog, ret) => folder.fold_program(prog, *ret),
+        Expr::BinOp(op, exp1, exp2) => folder.fold_binop(op, *exp1, *exp2),
+        Expr::Compare(first, rest) => folder.fold_compare(*first, rest),
+        Expr::UnaryOp(op, expr) => folder.fold_unaryop(op, *expr),
+        Expr::If(cond, exp1, exp2) => folder.fold_if(*cond, *exp1, *exp2),
+        Expr::Assign(name, ty, expr) => folder.fold_assign(name, ty, *expr),
+        Expr::Lambda(param, ty, body) => folder.fold_lambda(param, ty, *body),
+        Expr::App(fun, arg) => folder.fold_app(*fun, *arg),
+        Expr::Compose(first, then) => folder.fold_compose(*first, *then),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        desugar::desugar, desugar::parse_core, expression::Expr, ident::Ident, parse::Parser,
+    };
+
+    use super::{Fold, Visitor, VisitorMut};
+
+    #[test]
+    fn visitor_walks_every_node_in_pre_order() {
+        struct Count(usize);
+        impl Visitor for Count {
+            fn visit_expr(&mut self, expr: &Expr) {
+                self.0 += 1;
+                super::visit_expr(self, expr);
+            }
+        }
+        let src =
+            "infixl 6 <+> = plus; let f = lambda (n) { if (n < 1) { 0 } else { f(n - 1) } }; \
+                   2 |> (+ 1) >> f < 3 <= 4 <+> (10 -)(5)";
+        let (expr, spans) = desugar(&Parser::new(src).program().unwrap());
+        let mut count = Count(0);
+        count.visit_expr(&expr);
+        assert_eq!(count.0, spans.len());
+    }
+
+    #[test]
+    fn visitor_mut_renames_variables() {
+        struct Rename(Ident, Ident);
+        impl VisitorMut for Rename {
+            fn visit_variable_mut(&mut self, name: &mut Ident) {
+                if *name == self.0 {
+                    *name = self.1;
+                }
+            }
+        }
+        let mut expr = parse_core("let y = x + 1; lambda (z) { x * y }").unwrap();
+        Rename("x".into(), "w".into()).visit_expr_mut(&mut expr);
+        assert_eq!(
+            expr.to_string(),
+            "let y: ? = (w + Int(1)); lambda (z:?) {  (w * y) }"
+        );
+    }
+
+    #[test]
+    fn fold_rebuilds_the_rest() {
+        struct Double;
+        impl Fold for Double {
+            fn fold_int(&mut self, v: i64) -> Expr {
+                Expr::int(v * 2)
+            }
+        }
+        let expr = parse_core("if (1 < x) { f(2) } else { -3 }").unwrap();
+        assert_eq!(
+            Double.fold_expr(expr).to_string(),
+            " if ((Int(2) < x)) { f(Int(4)) } else { -Int(6) }"
+        );
+    }
+}
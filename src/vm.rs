@@ -0,0 +1,441 @@
+//! The bytecode virtual machine.
+//!
+//! All frames share one value stack: a frame's slots sit at its base, with
+//! the operands of the running instruction above them. Calls push a frame
+//! rather than recursing in Rust, so the depth of recursion is bounded only
+//! by the budget.
+
+use std::{error, fmt, rc::Rc};
+
+use anyhow::{bail, Ok, Result};
+
+use crate::{
+    budget::{Budget, Cancelled, ResourceExhausted},
+    bytecode::{Module, Op, Proto},
+    heap,
+    ident::Ident,
+    internal_value::{Closure, Lambda, Value},
+    resolve::Var,
+    span::Span,
+};
+
+/// An error raised by the program, with the span of the instruction that
+/// raised it.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)
+    }
+}
+
+impl error::Error for RuntimeError {}
+
+struct Frame {
+    proto: Rc<Proto>,
+    /// The running closure; `None` at the top level.
+    closure: Option<Rc<Closure>>,
+    /// The next instruction.
+    pc: usize,
+    /// The index of the frame's first slot on the stack.
+    base: usize,
+}
+
+pub struct Vm<'m> {
+    module: &'m Module,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    budget: &'m Budget,
+    /// Calls a composed function `f >> g` as a closure over `f` and `g`.
+    compose: Rc<Proto>,
+}
+
+impl<'m> Vm<'m> {
+    pub fn run(module: &'m Module, budget: &'m Budget) -> Result<Value> {
+        let main = Rc::clone(&module.protos[module.main]);
+        let mut vm = Vm {
+            module,
+            stack: vec![Value::Bool(false); main.frame_size],
+            frames: vec![Frame {
+                proto: main,
+                closure: None,
+                pc: 0,
+                base: 0,
+            }],
+            budget,
+            compose: Rc::new(compose_proto()),
+        };
+        vm.execute().map_err(|err| vm.locate(err))
+    }
+
+    /// Points an error of the program at the instruction that raised it, if
+    /// the module records its source. Running out of budget is not the
+    /// fault of any one instruction.
+    fn locate(&self, err: anyhow::Error) -> anyhow::Error {
+        if err.is::<ResourceExhausted>() || err.is::<Cancelled>() {
+            return err;
+        }
+        let Some(frame) = self.frames.last() else {
+            return err;
+        };
+        match frame.proto.span_at(frame.pc - 1) {
+            Some(span) => RuntimeError {
+                span,
+                message: err.to_string(),
+            }
+            .into(),
+            None => err,
+        }
+    }
+
+    fn execute(&mut self) -> Result<Value> {
+        loop {
+            self.budget.step()?;
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.proto.code[frame.pc];
+            frame.pc += 1;
+            match op {
+                Op::Const(index) => {
+                    let val = frame.proto.constants[index as usize].clone();
+                    self.stack.push(val);
+                }
+                Op::Local(slot) => {
+                    let val = self.stack[frame.base + slot as usize].clone();
+                    self.stack.push(val);
+                }
+                Op::SetLocal(slot) => {
+                    self.stack[frame.base + slot as usize] = self.stack.last().unwrap().clone();
+                }
+                Op::Capture(index) => {
+                    let val = capture(frame, index as usize);
+                    self.stack.push(val);
+                }
+                Op::Unbound => bail!("undefined variable"),
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::Swap => {
+                    let len = self.stack.len();
+                    self.stack.swap(len - 2, len - 1);
+                }
+                Op::BinOp(op) => {
+                    let v2 = self.pop();
+                    let v1 = self.pop();
+                    self.stack.push(op.apply(v1, v2)?);
+                }
+                Op::UnOp(op) => {
+                    let v1 = self.pop();
+                    self.stack.push(op.apply(v1)?);
+                }
+                Op::Compare(op, fail) => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    if op.apply(lhs, rhs.clone())? == Value::Bool(false) {
+                        self.jump(fail);
+                    } else {
+                        self.stack.push(rhs);
+                    }
+                }
+                Op::Jump(target) => frame.pc = target as usize,
+                Op::JumpIfFalse(target) => match self.pop() {
+                    Value::Bool(true) => {}
+                    Value::Bool(false) => self.jump(target),
+                    _ => bail!("if expression: non-bool condition!"),
+                },
+                Op::Closure(index) => {
+                    let val = self.closure(index as usize);
+                    self.stack.push(val);
+                }
+                Op::Compose => {
+                    let then = self.pop();
+                    let first = self.pop();
+                    self.stack
+                        .push(Value::Compose(Box::new(first), Box::new(then)));
+                }
+                Op::Call => self.call(false)?,
+                Op::TailCall => self.call(true)?,
+                Op::Return => {
+                    let val = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.stack.truncate(frame.base);
+                    if frame.closure.is_some() {
+                        self.budget.leave();
+                    }
+                    if self.frames.is_empty() {
+                        return Ok(val);
+                    }
+                    self.stack.push(val);
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.frames.last_mut().unwrap().pc = target as usize;
+    }
+
+    /// Builds a closure of function `index` from the current frame.
+    fn closure(&mut self, index: usize) -> Value {
+        let proto = &self.module.protos[index];
+        let frame = self.frames.last().unwrap();
+        let captures = proto
+            .captures
+            .iter()
+            .enumerate()
+            .map(|(i, (_, var))| match (proto.recursive, var) {
+                // A placeholder until the closure exists.
+                (Some(rec), _) if rec == i => Value::Bool(false),
+                (_, Var::Local(slot)) => self.stack[frame.base + slot].clone(),
+                (_, Var::Capture(index)) => capture(frame, *index),
+            })
+            .collect();
+        let closure = heap::alloc(Closure::new(Lambda::Compiled(Rc::clone(proto)), captures));
+        if let Some(rec) = proto.recursive {
+            closure.captures.borrow_mut()[rec] = Value::Lambda(Rc::clone(&closure));
+        }
+        Value::Lambda(closure)
+    }
+
+    fn call(&mut self, tail: bool) -> Result<()> {
+        let arg = self.pop();
+        let closure = match self.pop() {
+            Value::Lambda(closure) => closure,
+            Value::Compose(first, then) => heap::alloc(Closure::new(
+                Lambda::Compiled(Rc::clone(&self.compose)),
+                vec![*first, *then],
+            )),
+            _ => bail!("eval error: application to non-lambda!"),
+        };
+        let Lambda::Compiled(proto) = &closure.fun else {
+            unreachable!("resolved closure in the VM");
+        };
+        let proto = Rc::clone(proto);
+        // A tail call takes over the depth of the call it replaces, but the
+        // top level has none to give.
+        let replaces_call = tail && {
+            let frame = self.frames.pop().unwrap();
+            self.stack.truncate(frame.base);
+            frame.closure.is_some()
+        };
+        if !replaces_call {
+            self.budget.enter()?;
+        }
+        let base = self.stack.len();
+        self.stack.push(arg);
+        self.stack
+            .resize(base + proto.frame_size, Value::Bool(false));
+        self.frames.push(Frame {
+            proto,
+            closure: Some(closure),
+            pc: 0,
+            base,
+        });
+        Ok(())
+    }
+}
+
+fn capture(frame: &Frame, index: usize) -> Value {
+    match &frame.closure {
+        Some(closure) => closure.captures.borrow()[index].clone(),
+        None => unreachable!("captured variable outside of a closure"),
+    }
+}
+
+/// `(first >> then)(x)`, with `first` and `then` as upvalues.
+fn compose_proto() -> Proto {
+    Proto {
+        param: Ident::intern("x"),
+        captures: vec![
+            (Ident::intern("first"), Var::Capture(0)),
+            (Ident::intern("then"), Var::Capture(1)),
+        ],
+        recursive: None,
+        frame_size: 1,
+        constants: vec![],
+        code: vec![
+            Op::Capture(1),
+            Op::Capture(0),
+            Op::Local(0),
+            Op::Call,
+            Op::TailCall,
+        ],
+        spans: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        budget::{Limits, ResourceExhausted},
+        bytecode::Module,
+        corpus::{FAILING, PROGRAMS},
+        desugar::desugar,
+        desugar::parse_core,
+        eval::{Backend, Eval},
+        heap,
+        internal_value::Value,
+        parse::Parser,
+        resolve::Resolver,
+    };
+
+    use super::RuntimeError;
+
+    fn run(src: &str) -> anyhow::Result<Value> {
+        let expr = parse_core(src).unwrap();
+        Eval::new().with_backend(Backend::Bytecode).eval(&expr)
+    }
+
+    #[test]
+    fn vm_matches_eval() {
+        let programs = PROGRAMS.iter().copied().chain([
+            "let sum = lambda (acc: int) { lambda (n: int) { if (n == 0) { acc } else { sum(acc + n)(n - 1) } } }; sum(0)(100000)",
+        ]);
+        for src in programs {
+            let expr = parse_core(src).unwrap();
+            assert_eq!(run(src).unwrap(), Eval::new().eval(&expr).unwrap(), "{src}");
+        }
+        assert_eq!(run("(+ 1)").unwrap().to_string(), "lambda ($lhs)");
+    }
+
+    #[test]
+    fn vm_errors_match_eval() {
+        let programs = FAILING.iter().copied().chain([
+            "let g = lambda (u: int) { y }; let y = 1; g(0)",
+            "let f = 1; f(2)",
+        ]);
+        for src in programs {
+            let expr = parse_core(src).unwrap();
+            assert_eq!(
+                run(src).unwrap_err().to_string(),
+                Eval::new().eval(&expr).unwrap_err().to_string(),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn vm_errors_point_at_the_source() {
+        let error = |src: &str| {
+            let syntax = Parser::new(src).program().unwrap();
+            let (expr, spans) = desugar(&syntax);
+            let module = Module::compile_with_spans(&Resolver::resolve(&expr), &spans);
+            let err = Eval::new().eval_module(&module).unwrap_err();
+            assert!(err.is::<RuntimeError>(), "{src}");
+            err.to_string()
+        };
+        assert_eq!(
+            error("let zero = 0; 1 + 1 / zero"),
+            "division by zero at 18..26"
+        );
+        assert_eq!(
+            error("let f = lambda (n: int) { n(1) }; f(2)"),
+            "eval error: application to non-lambda! at 26..30"
+        );
+        assert_eq!(
+            error("let g = lambda (u: int) { y }; let y = 1; g(0)"),
+            "undefined variable at 26..27"
+        );
+    }
+
+    #[test]
+    fn vm_runs_deep_recursion_within_limits() {
+        assert_eq!(
+            run("let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(200000)")
+                .unwrap(),
+            Value::Int(200000)
+        );
+        let expr = parse_core(
+            "let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(1000)",
+        )
+        .unwrap();
+        let err = Eval::new()
+            .with_backend(Backend::Bytecode)
+            .with_limits(Limits {
+                call_depth: Some(100),
+                ..Limits::default()
+            })
+            .eval(&expr)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ResourceExhausted::CallDepth(100)));
+    }
+
+    #[test]
+    fn vm_call_depth_matches_eval() {
+        let limits = Limits {
+            call_depth: Some(10),
+            ..Limits::default()
+        };
+        // The deepest recursion each backend runs within the limit.
+        let deepest = |backend, call: &str| {
+            (0..20)
+                .take_while(|n| {
+                    let src = format!(
+                        "let f = lambda (n: int) {{ if (n == 0) {{ 0 }} else {{ f(n - 1) + 1 }} }}; {}",
+                        call.replace('N', &n.to_string())
+                    );
+                    let expr = parse_core(&src).unwrap();
+                    Eval::new()
+                        .with_backend(backend)
+                        .with_limits(limits)
+                        .eval(&expr)
+                        .is_ok()
+                })
+                .count()
+        };
+        for call in [
+            "f(N)",
+            "1 + f(N)",
+            "((+ 1) >> f)(N)",
+            "let g = f >> (+ 1); g(N)",
+        ] {
+            let tree = deepest(Backend::Tree, call);
+            assert!(tree > 0 && tree < 20, "{call}");
+            assert_eq!(deepest(Backend::Bytecode, call), tree, "{call}");
+            assert_eq!(deepest(Backend::Machine(1 << 30), call), tree, "{call}");
+        }
+    }
+
+    #[test]
+    fn composed_calls_evaluate_the_functions_first() {
+        let limits = Limits {
+            call_depth: Some(10),
+            ..Limits::default()
+        };
+        let defs =
+            "let zero = 0; let f = lambda (n: int) { if (n == 0) { 0 } else { f(n - 1) + 1 } };";
+        let fails = "(if (1 / zero == 0) { f } else { f })";
+        // Both a function and the argument fail; the functions come first.
+        for call in [
+            format!("({fails} >> f)(f(100))"),
+            format!("(f >> {fails})(f(100))"),
+        ] {
+            let expr = parse_core(&format!("{defs} {call}")).unwrap();
+            for backend in [Backend::Tree, Backend::Machine(1 << 30), Backend::Bytecode] {
+                let err = Eval::new()
+                    .with_backend(backend)
+                    .with_limits(limits)
+                    .eval(&expr)
+                    .unwrap_err();
+                assert!(
+                    err.to_string().starts_with("division by zero"),
+                    "{call}: {err}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn vm_frees_recursive_closures() {
+        run("let f = lambda (n: int) { if (n < 1) { 0 } else { f(n - 1) } }; f(10)").unwrap();
+        heap::collect();
+        assert_eq!(heap::live_closures(), 0);
+    }
+}
@@ -0,0 +1,386 @@
+//! Compilation of type-checked expressions to WebAssembly text.
+//!
+//! The resolver does the closure conversion: every function becomes a wasm
+//! function taking its closure and its argument, and a closure is a block
+//! of linear memory holding the function's index in the table followed by
+//! its captured values. All values are `i64`s: ints as themselves, bools as
+//! 0 or 1 and closures as their address. Memory is never freed.
+//!
+//! The module exports `main`, which returns the value of the program. A
+//! runtime error sets the exported global `error` to an index into
+//! `ERRORS` plus one and traps. Calls in tail position use
+//! `return_call_indirect`, so the runtime must support tail calls.
+
+use std::fmt::Write;
+
+use crate::{
+    expression::Expr,
+    operator::{BinOp, UnOp},
+    resolve::{Code, Function, Resolver, Var},
+};
+
+/// The messages of the runtime errors, as `Eval` reports them.
+pub const ERRORS: [&str; 2] = ["division by zero", "undefined variable"];
+
+const RUNTIME: &str = r#"  (type $fun (func (param i32 i64) (result i64)))
+  (memory (export "memory") 1)
+  (global $heap (mut i32) (i32.const 8))
+  (global $error (export "error") (mut i32) (i32.const 0))
+
+  ;; Allocates `size` bytes, a multiple of 8, growing the memory as needed.
+  (func $alloc (param $size i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (global.get $heap) (local.get $size)))
+    (if (i32.gt_u (global.get $heap) (i32.shl (memory.size) (i32.const 16)))
+      (then
+        (if (i32.eq
+              (memory.grow
+                (i32.sub
+                  (i32.shr_u (i32.add (global.get $heap) (i32.const 0xffff)) (i32.const 16))
+                  (memory.size)))
+              (i32.const -1))
+          (then (unreachable)))))
+    (local.get $ptr))
+
+  ;; Fails with the runtime error `code`.
+  (func $fail (param $code i32) (result i64)
+    (global.set $error (local.get $code))
+    (unreachable))
+
+  (func $div (param $a i64) (param $b i64) (result i64)
+    (if (i64.eqz (local.get $b))
+      (then (return (call $fail (i32.const 1)))))
+    ;; `i64.div_s` traps on overflow, where ours wraps.
+    (if (i64.eq (local.get $b) (i64.const -1))
+      (then (return (i64.sub (i64.const 0) (local.get $a)))))
+    (i64.div_s (local.get $a) (local.get $b)))
+
+  ;; `(f >> g)(x)`, with `f` and `g` captured.
+  (func $compose (type $fun) (param $env i32) (param $s0 i64) (result i64)
+    (local $f i64)
+    (local.set $f (i64.load offset=8 (local.get $env)))
+    (local.set $s0
+      (call_indirect (type $fun)
+        (i32.wrap_i64 (local.get $f))
+        (local.get $s0)
+        (i32.load (i32.wrap_i64 (local.get $f)))))
+    (local.set $f (i64.load offset=16 (local.get $env)))
+    (return_call_indirect (type $fun)
+      (i32.wrap_i64 (local.get $f))
+      (local.get $s0)
+      (i32.load (i32.wrap_i64 (local.get $f)))))
+"#;
+
+/// The table index of `$compose`; the compiled functions follow it.
+const COMPOSE: usize = 0;
+
+pub struct WatGen {
+    /// The compiled functions, in table order after `$compose`.
+    funs: Vec<String>,
+}
+
+/// The body of the function being compiled.
+struct Body {
+    code: String,
+    depth: usize,
+    /// The number of `i64` temporaries used.
+    temps: usize,
+}
+
+impl WatGen {
+    /// Compiles a type-checked program to a WAT module.
+    pub fn compile(expr: &Expr) -> String {
+        let program = Resolver::resolve(expr);
+        let mut gen = WatGen { funs: vec![] };
+        let mut body = Body::new();
+        gen.code(&mut body, &program.code, true);
+
+        let mut module = String::from("(module\n");
+        module.push_str(RUNTIME);
+        writeln!(module, "\n  (table {} funcref)", gen.funs.len() + 1).unwrap();
+        write!(module, "  (elem (i32.const 0) func $compose").unwrap();
+        for i in 0..gen.funs.len() {
+            write!(module, " $f{i}").unwrap();
+        }
+        module.push_str(")\n");
+        for fun in &gen.funs {
+            module.push('\n');
+            module.push_str(fun);
+        }
+        module.push_str("\n  (func (export \"main\") (result i64)\n");
+        module.push_str(&body.locals(0, program.frame_size));
+        module.push_str(&body.code);
+        module.push_str("  )\n)\n");
+        module
+    }
+
+    /// Compiles a function, returning its index in the table.
+    fn function(&mut self, fun: &Function) -> usize {
+        let index = self.funs.len();
+        // Claim a table slot before compiling the body, whose own functions
+        // take the slots after it.
+        self.funs.push(String::new());
+        let mut body = Body::new();
+        self.code(&mut body, &fun.body, true);
+        let mut out = format!(
+            "  ;; lambda ({})\n  (func $f{index} (type $fun) (param $env i32) (param $s0 i64) (result i64)\n",
+            fun.param
+        );
+        out.push_str(&body.locals(1, fun.frame_size));
+        out.push_str(&body.code);
+        out.push_str("  )\n");
+        self.funs[index] = out;
+        index + 1
+    }
+
+    /// Compiles `code` to leave its value on the stack. Calls in `tail`
+    /// position return the callee's result directly.
+    fn code(&mut self, body: &mut Body, code: &Code, tail: bool) {
+        match code {
+            Code::Int(v) => body.emit(format!("i64.const {v}")),
+            Code::Bool(v) => body.emit(format!("i64.const {}", *v as i64)),
+            Code::Var(var) => body.var(*var),
+            Code::Unbound(_) => body.emit("i32.const 2\ncall $fail"),
+            Code::Program(prog, ret) => {
+                for code in prog {
+                    self.code(body, code, false);
+                    body.emit("drop");
+                }
+                self.code(body, ret, tail);
+            }
+            Code::Let(slot, code) => {
+                self.code(body, code, false);
+                body.emit(format!("local.tee $s{slot}"));
+            }
+            Code::BinOp(op, exp1, exp2) => {
+                self.code(body, exp1, false);
+                self.code(body, exp2, false);
+                body.binop(*op);
+            }
+            Code::Compare(first, rest) => {
+                // The right operand of each link is the left of the next, so
+                // the operands alternate between two temporaries.
+                let temps = [body.temp(), body.temp()];
+                body.open("block $chain (result i64)");
+                body.open("block $fail");
+                self.code(body, first, false);
+                body.emit(format!("local.set $t{}", temps[0]));
+                for (i, (op, code)) in rest.iter().enumerate() {
+                    let (lhs, rhs) = (temps[i % 2], temps[(i + 1) % 2]);
+                    self.code(body, code, false);
+                    body.emit(format!("local.set $t{rhs}"));
+                    body.emit(format!("local.get $t{lhs}\nlocal.get $t{rhs}"));
+                    body.emit(format!("{}\ni32.eqz\nbr_if $fail", comparison(*op)));
+                }
+                body.emit("i64.const 1\nbr $chain");
+                body.close();
+                body.emit("i64.const 0");
+                body.close();
+            }
+            Code::UnaryOp(UnOp::Neg, exp1) => {
+                body.emit("i64.const 0");
+                self.code(body, exp1, false);
+                body.emit("i64.sub");
+            }
+            Code::UnaryOp(UnOp::Not, exp1) => {
+                self.code(body, exp1, false);
+                body.emit("i64.eqz\ni64.extend_i32_u");
+            }
+            Code::If(cond, exp1, exp2) => {
+                self.code(body, cond, false);
+                body.emit("i32.wrap_i64");
+                body.open("if (result i64)");
+                self.code(body, exp1, tail);
+                body.depth -= 1;
+                body.emit("else");
+                body.depth += 1;
+                self.code(body, exp2, tail);
+                body.close();
+            }
+            Code::Lambda(fun) => {
+                let index = self.function(fun);
+                body.closure(index, fun.captures.len(), |body, i| match fun.recursive {
+                    Some(rec) if rec == i => body.emit("local.get $clo\ni64.extend_i32_u"),
+                    _ => body.var(fun.captures[i].1),
+                });
+            }
+            Code::App(fun, arg) => {
+                let temp = body.temp();
+                self.code(body, fun, false);
+                body.emit(format!("local.set $t{temp}"));
+                body.emit(format!("local.get $t{temp}\ni32.wrap_i64"));
+                self.code(body, arg, false);
+                body.emit(format!("local.get $t{temp}\ni32.wrap_i64\ni32.load"));
+                let call = if tail {
+                    "return_call_indirect"
+                } else {
+                    "call_indirect"
+                };
+                body.emit(format!("{call} (type $fun)"));
+            }
+            Code::Compose(first, then) => {
+                let temps = [body.temp(), body.temp()];
+                self.code(body, first, false);
+                body.emit(format!("local.set $t{}", temps[0]));
+                self.code(body, then, false);
+                body.emit(format!("local.set $t{}", temps[1]));
+                body.closure(COMPOSE, 2, |body, i| {
+                    body.emit(format!("local.get $t{}", temps[i]))
+                });
+            }
+        }
+    }
+}
+
+impl Body {
+    fn new() -> Body {
+        Body {
+            code: String::new(),
+            depth: 2,
+            temps: 0,
+        }
+    }
+
+    /// Writes instructions, one per line, at the current depth.
+    fn emit(&mut self, instrs: impl AsRef<str>) {
+        for instr in instrs.as_ref().lines() {
+            writeln!(self.code, "{}{instr}", "  ".repeat(self.depth)).unwrap();
+        }
+    }
+
+    fn open(&mut self, instr: &str) {
+        self.emit(instr);
+        self.depth += 1;
+    }
+
+    fn close(&mut self) {
+        self.depth -= 1;
+        self.emit("end");
+    }
+
+    fn temp(&mut self) -> usize {
+        self.temps += 1;
+        self.temps - 1
+    }
+
+    fn var(&mut self, var: Var) {
+        match var {
+            Var::Local(slot) => self.emit(format!("local.get $s{slot}")),
+            Var::Capture(i) => self.emit(format!("local.get $env\ni64.load offset={}", 8 + 8 * i)),
+        }
+    }
+
+    /// Allocates a closure of the function at `index` in the table, with
+    /// `capture` pushing each captured value.
+    fn closure(&mut self, index: usize, captures: usize, capture: impl Fn(&mut Body, usize)) {
+        self.emit(format!(
+            "i32.const {}\ncall $alloc\nlocal.tee $clo",
+            8 + 8 * captures
+        ));
+        self.emit(format!("i32.const {index}\ni32.store"));
+        for i in 0..captures {
+            self.emit("local.get $clo");
+            capture(self, i);
+            self.emit(format!("i64.store offset={}", 8 + 8 * i));
+        }
+        self.emit("local.get $clo\ni64.extend_i32_u");
+    }
+
+    fn binop(&mut self, op: BinOp) {
+        match op {
+            BinOp::Add => self.emit("i64.add"),
+            BinOp::Sub => self.emit("i64.sub"),
+            BinOp::Mul => self.emit("i64.mul"),
+            BinOp::Div => self.emit("call $div"),
+            BinOp::And => self.emit("i64.and"),
+            BinOp::Or => self.emit("i64.or"),
+            op => self.emit(format!("{}\ni64.extend_i32_u", comparison(op))),
+        }
+    }
+
+    /// Declares the slots from `first` on and the scratch locals.
+    fn locals(&self, first: usize, frame_size: usize) -> String {
+        let mut out = String::new();
+        for slot in first..frame_size {
+            writeln!(out, "    (local $s{slot} i64)").unwrap();
+        }
+        for temp in 0..self.temps {
+            writeln!(out, "    (local $t{temp} i64)").unwrap();
+        }
+        out.push_str("    (local $clo i32)\n");
+        out
+    }
+}
+
+fn comparison(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Eq => "i64.eq",
+        BinOp::Ne => "i64.ne",
+        BinOp::Lt => "i64.lt_s",
+        BinOp::Gt => "i64.gt_s",
+        BinOp::Le => "i64.le_s",
+        BinOp::Ge => "i64.ge_s",
+        op => unreachable!("{op:?} is not a comparison"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmi::{Config, Engine, Linker, Module, Store, Val};
+
+    use crate::{
+        corpus::{FAILING, PROGRAMS},
+        desugar::parse_core,
+        eval::Eval,
+        internal_value::Value,
+    };
+
+    use super::{WatGen, ERRORS};
+
+    /// Runs the compiled program, returning `main`'s result or the error.
+    fn run_wasm(src: &str) -> Result<i64, String> {
+        let wat = WatGen::compile(&parse_core(src).unwrap());
+        let wasm = wat::parse_str(&wat).unwrap_or_else(|err| panic!("{err}\n{wat}"));
+        let mut config = Config::default();
+        config.wasm_tail_call(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &wasm).unwrap_or_else(|err| panic!("{err}\n{wat}"));
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .unwrap();
+        let main = instance.get_typed_func::<(), i64>(&store, "main").unwrap();
+        main.call(&mut store, ()).map_err(|trap| {
+            match instance.get_global(&store, "error").unwrap().get(&store) {
+                Val::I32(code) if code > 0 => ERRORS[code as usize - 1].to_string(),
+                _ => trap.to_string(),
+            }
+        })
+    }
+
+    fn check(src: &str) {
+        let expr = parse_core(src).unwrap();
+        match (Eval::new().eval(&expr), run_wasm(src)) {
+            (Ok(Value::Int(v)), Ok(w)) => assert_eq!(v, w, "{src}"),
+            (Ok(Value::Bool(v)), Ok(w)) => assert_eq!(v as i64, w, "{src}"),
+            (Err(err), Err(trap)) => assert_eq!(err.to_string(), trap, "{src}"),
+            (val, res) => panic!("{src}: {val:?} but {res:?}"),
+        }
+    }
+
+    #[test]
+    fn watgen_matches_eval() {
+        for src in PROGRAMS.iter().chain(FAILING) {
+            check(src);
+        }
+    }
+
+    #[test]
+    fn watgen_reports_errors_and_runs_tail_calls() {
+        check("let g = lambda (u: int) { y }; let y = 1; g(0)");
+        // Enough closures to grow the memory, in constant stack.
+        check("let sum = lambda (acc: int) { lambda (n: int) { if (n == 0) { acc } else { sum(acc + n)(n - 1) } } }; sum(0)(100000)");
+    }
+}